@@ -0,0 +1,399 @@
+// A copy of the matcher as it was before choices were preprocessed when they're read, which decodes
+// every choice into a `Vec<char>` each time it's matched. Only what's needed to compute matches is
+// kept, so that the benchmark can compare the two on the same corpus.
+
+use heatseeker::matching::CaseMode;
+use rayon::prelude::*;
+
+// Bonuses awarded to matched characters, as a fraction of the character's share of the score. A
+// run of consecutive matches inherits the bonus of the character that begins it, so that `bar`
+// matching the word "bar" in "foo_bar" is rewarded as a whole.
+const BOUNDARY_BONUS: f64 = 0.5;
+const CAMEL_CASE_BONUS: f64 = 0.4;
+const BASENAME_BONUS: f64 = 0.3;
+
+macro_rules! chars {
+    ($str:expr) => {
+        &$str.chars().collect::<Vec<char>>()
+    };
+}
+
+// A parsed search query. Whitespace separates the query into terms, all of which must match. A
+// term is matched fuzzily unless it uses one of the following operators:
+//
+//   'term   exact substring match
+//   ^term   prefix match
+//   term$   suffix match
+//   ^term$  exact match of the entire choice
+//   !term   excludes choices containing `term` (may be combined with `^` and `$`)
+//
+// A backslash escapes a space, allowing it to be included in a term. Terms that are empty once
+// their operators are stripped (e.g. a lone `!` while the user is still typing) are ignored.
+struct Query {
+    terms: Vec<Term>,
+    case: CaseMode,
+}
+
+#[derive(Debug, PartialEq)]
+struct Term {
+    text: Vec<char>,
+    kind: TermKind,
+    negated: bool,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum TermKind {
+    Fuzzy,
+    Exact,
+    Prefix,
+    Suffix,
+    Equal,
+}
+
+impl Query {
+    fn parse(query: &str, case: CaseMode) -> Query {
+        let mut terms = Vec::new();
+        let mut token = String::new();
+        let mut chars = query.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c == '\\' && chars.peek().is_some_and(|c| c.is_whitespace()) {
+                token.push(chars.next().unwrap());
+            } else if c.is_whitespace() {
+                terms.extend(Term::parse(&token));
+                token.clear();
+            } else {
+                token.push(c);
+            }
+        }
+        terms.extend(Term::parse(&token));
+        Query { terms, case }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+}
+
+impl Term {
+    fn parse(token: &str) -> Option<Term> {
+        let mut text = token;
+        let negated = text.starts_with('!');
+        if negated {
+            text = &text[1..];
+        }
+        let kind = if let Some(rest) = text.strip_prefix('\'') {
+            text = rest;
+            TermKind::Exact
+        } else {
+            let prefix = text.starts_with('^');
+            if prefix {
+                text = &text[1..];
+            }
+            let suffix = text.ends_with('$');
+            if suffix {
+                text = &text[..text.len() - 1];
+            }
+            match (prefix, suffix) {
+                (true, true) => TermKind::Equal,
+                (true, false) => TermKind::Prefix,
+                (false, true) => TermKind::Suffix,
+                // Fuzzy exclusion is almost never what anyone wants, so negated terms are exact.
+                (false, false) if negated => TermKind::Exact,
+                (false, false) => TermKind::Fuzzy,
+            }
+        };
+        if text.is_empty() {
+            return None;
+        }
+        Some(Term {
+            text: text.chars().collect(),
+            kind,
+            negated,
+        })
+    }
+
+    // Returns the bounds of the best match for this term within the choice, ignoring negation.
+    fn find(&self, choice: &[char], case: CaseMode) -> Option<(usize, usize)> {
+        let len = self.text.len();
+        if len > choice.len() {
+            return None;
+        }
+        let start = match self.kind {
+            TermKind::Fuzzy => return get_longest_match(choice, &self.text, case),
+            TermKind::Exact => find_substring(choice, &self.text, case)?,
+            TermKind::Prefix => 0,
+            TermKind::Suffix => choice.len() - len,
+            TermKind::Equal if len == choice.len() => 0,
+            TermKind::Equal => return None,
+        };
+        if substring_at(choice, &self.text, start, case) {
+            Some((start, start + len - 1))
+        } else {
+            None
+        }
+    }
+
+    // Returns the score and matched indices of the highest-scoring match for this term within the
+    // choice, ignoring negation.
+    fn best_match(&self, choice: &[char], case: CaseMode) -> Option<(f64, Vec<usize>)> {
+        let len = self.text.len();
+        if len > choice.len() {
+            return None;
+        }
+        let starts = match self.kind {
+            TermKind::Fuzzy | TermKind::Exact => 0..choice.len() - len + 1,
+            TermKind::Prefix => 0..1,
+            TermKind::Suffix => choice.len() - len..choice.len() - len + 1,
+            TermKind::Equal if len == choice.len() => 0..1,
+            TermKind::Equal => return None,
+        };
+        let basename_start = basename_start(choice);
+
+        let mut best: Option<(f64, Vec<usize>)> = None;
+        for start in starts {
+            let indices = if self.kind == TermKind::Fuzzy {
+                if !chars_equal(self.text[0], choice[start], case) {
+                    continue;
+                }
+                match get_match_indices(choice, &self.text[1..], start, case) {
+                    Some(indices) => indices,
+                    // If the rest of the term can't be found after this point, it can't be found
+                    // after any later one either.
+                    None => break,
+                }
+            } else if substring_at(choice, &self.text, start, case) {
+                (start..start + len).collect()
+            } else {
+                continue;
+            };
+            let score = score_indices(choice, &indices, basename_start);
+            if best
+                .as_ref()
+                .is_none_or(|(best_score, _)| score > *best_score)
+            {
+                best = Some((score, indices));
+            }
+        }
+        best
+    }
+}
+
+// Matches the given candidates (indices into `choices`) against the query, returning the index and
+// score of every match, in no particular order.
+pub fn compute_matches<S: AsRef<str> + Sync>(
+    choices: &[S],
+    candidates: &[usize],
+    query: &str,
+    case: CaseMode,
+) -> Vec<(usize, f64)> {
+    let query = Query::parse(query, case);
+    let query = &query;
+    candidates
+        .par_iter()
+        .with_min_len(100)
+        .map(|&i| (i, score(choices[i].as_ref(), query)))
+        .filter(|&(_, score)| score > 0.0)
+        .collect()
+}
+
+fn score(choice: &str, query: &Query) -> f64 {
+    if query.is_empty() {
+        return 1.0;
+    }
+
+    let choice = chars!(choice);
+
+    let mut score = 1.0;
+    let mut positive_terms = 0;
+    for term in &query.terms {
+        if term.negated {
+            if term.find(choice, query.case).is_some() {
+                return 0.0;
+            }
+        } else if let Some((term_score, _)) = term.best_match(choice, query.case) {
+            score *= term_score;
+            positive_terms += 1;
+        } else {
+            return 0.0;
+        }
+    }
+
+    // Taking the geometric mean keeps scores comparable regardless of how many terms there are.
+    if positive_terms == 0 {
+        1.0
+    } else {
+        score.powf(1.0 / positive_terms as f64) / choice.len() as f64
+    }
+}
+
+// Scores a single term's match. The score starts out as the ratio of the term's length to the
+// length of the span it matched, which penalizes gaps between the matched characters. That ratio
+// is then scaled up by the bonuses earned by the matched characters, favoring matches that start
+// at word boundaries (after `/`, `_`, `-`, `.`, or a space, or at a camelCase hump) and matches
+// in the basename of a path.
+fn score_indices(choice: &[char], indices: &[usize], basename_start: usize) -> f64 {
+    let span = indices[indices.len() - 1] - indices[0] + 1;
+    let mut bonus = 0.0;
+    let mut run_bonus = 0.0;
+    for (n, &idx) in indices.iter().enumerate() {
+        if n == 0 || indices[n - 1] + 1 != idx {
+            run_bonus = boundary_bonus(choice, idx);
+        }
+        bonus += run_bonus.max(boundary_bonus(choice, idx));
+        if idx >= basename_start {
+            bonus += BASENAME_BONUS;
+        }
+    }
+    let ratio = indices.len() as f64 / span as f64;
+    ratio * (1.0 + bonus / indices.len() as f64)
+}
+
+fn boundary_bonus(choice: &[char], idx: usize) -> f64 {
+    if idx == 0 {
+        return BOUNDARY_BONUS;
+    }
+    let (prev, c) = (choice[idx - 1], choice[idx]);
+    if is_separator(prev) || matches!(prev, '_' | '-' | '.' | ' ') {
+        BOUNDARY_BONUS
+    } else if prev.is_lowercase() && c.is_uppercase() {
+        CAMEL_CASE_BONUS
+    } else {
+        0.0
+    }
+}
+
+fn basename_start(choice: &[char]) -> usize {
+    choice
+        .iter()
+        .rposition(|&c| is_separator(c))
+        .map_or(0, |i| i + 1)
+}
+
+fn is_separator(c: char) -> bool {
+    c == '/' || c == '\\'
+}
+
+fn get_longest_match(string: &[char], query: &[char], case: CaseMode) -> Option<(usize, usize)> {
+    let first_char = query[0];
+    let rest = &query[1..];
+    let indices = find_char_in_string(string, first_char, case);
+
+    let mut current_bounds: Option<(usize, usize)> = None;
+    let smallest_possible_match = query.len();
+    for &i in &indices {
+        if let Some(last_index) = find_end_of_match(string, rest, i, case) {
+            let last_bounds = Some((i, last_index));
+            let last_match_len = get_match_length(last_bounds).unwrap();
+            if current_bounds.is_none()
+                || last_match_len < get_match_length(current_bounds).unwrap()
+            {
+                current_bounds = last_bounds;
+                if last_match_len == smallest_possible_match {
+                    break;
+                }
+            }
+        }
+    }
+    current_bounds
+}
+
+fn get_match_length(bounds: Option<(usize, usize)>) -> Option<usize> {
+    if let Some((lb, ub)) = bounds {
+        Some(ub - lb + 1)
+    } else {
+        None
+    }
+}
+
+fn find_char_in_string(string: &[char], char: char, case: CaseMode) -> Vec<usize> {
+    let mut indices = Vec::new();
+    for (i, c) in string.iter().enumerate() {
+        if chars_equal(char, *c, case) {
+            indices.push(i);
+        }
+    }
+    indices
+}
+
+fn find_end_of_match(
+    string: &[char],
+    rest_of_query: &[char],
+    first_index: usize,
+    case: CaseMode,
+) -> Option<usize> {
+    get_match_indices(string, rest_of_query, first_index, case)
+        .map(|indices| indices[indices.len() - 1])
+}
+
+fn get_match_indices(
+    string: &[char],
+    rest_of_query: &[char],
+    first_index: usize,
+    case: CaseMode,
+) -> Option<Vec<usize>> {
+    let mut ret = Vec::new();
+    let mut last_index = first_index + 1;
+    ret.push(first_index);
+    for c in rest_of_query {
+        let current_substring = &string[last_index..];
+        let mut index = None;
+        for (i, x) in current_substring.iter().enumerate() {
+            if chars_equal(*c, *x, case) {
+                index = Some(i);
+                break;
+            }
+        }
+        if let Some(idx) = index {
+            last_index += idx + 1;
+            ret.push(last_index - 1);
+        } else {
+            return None;
+        }
+    }
+    Some(ret)
+}
+
+fn find_substring(string: &[char], substring: &[char], case: CaseMode) -> Option<usize> {
+    (0..=string.len() - substring.len()).find(|&i| substring_at(string, substring, i, case))
+}
+
+fn substring_at(string: &[char], substring: &[char], start: usize, case: CaseMode) -> bool {
+    string[start..start + substring.len()]
+        .iter()
+        .zip(substring)
+        .all(|(&c, &q)| chars_equal(q, c, case))
+}
+
+fn chars_equal(q: char, c: char, case: CaseMode) -> bool {
+    match case {
+        CaseMode::Respect => q == c,
+        CaseMode::Ignore => q == c || fold_case(q) == fold_case(c),
+        CaseMode::Smart => q == c || (!q.is_uppercase() && fold_case(q) == fold_case(c)),
+    }
+}
+
+// Simple (one-to-one) Unicode case folding. Characters whose lowercase form expands to more than
+// one character, such as 'İ', are left as they are.
+fn fold_case(c: char) -> char {
+    if c.is_ascii() {
+        return c.to_ascii_lowercase();
+    }
+    match c {
+        'ς' => 'σ',
+        'ϐ' => 'β',
+        'ϑ' => 'θ',
+        'ϕ' => 'φ',
+        'ϖ' => 'π',
+        'ϰ' => 'κ',
+        'ϱ' => 'ρ',
+        'ϵ' => 'ε',
+        'ſ' => 's',
+        _ => {
+            let mut lower = c.to_lowercase();
+            match (lower.next(), lower.next()) {
+                (Some(l), None) => l,
+                _ => c,
+            }
+        }
+    }
+}
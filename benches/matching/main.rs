@@ -0,0 +1,111 @@
+// Times compute_matches over a synthetic corpus of a million paths, next to the matcher from before
+// choices were preprocessed when they're read (see baseline.rs). Run with
+// `cargo bench --bench matching`.
+
+mod baseline;
+
+use heatseeker::matching::{self, CaseMode, Choice};
+use std::time::Instant;
+
+const CORPUS_SIZE: usize = 1_000_000;
+const QUERIES: &[&str] = &["s", "sm", "srcmain", "matching.rs", "zzz"];
+
+const WORDS: &[&str] = &[
+    "src",
+    "lib",
+    "test",
+    "main",
+    "matching",
+    "screen",
+    "unix",
+    "windows",
+    "search",
+    "input",
+    "config",
+    "util",
+    "core",
+    "app",
+    "models",
+    "views",
+    "controllers",
+    "spec",
+    "docs",
+    "assets",
+];
+const EXTENSIONS: &[&str] = &["rs", "rb", "py", "js", "md", "toml", "txt", "json"];
+
+// A fixed xorshift generator keeps the corpus identical between runs.
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+
+    fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+        items[self.below(items.len())]
+    }
+}
+
+fn corpus() -> Vec<String> {
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    (0..CORPUS_SIZE)
+        .map(|_| {
+            let mut path = String::new();
+            for _ in 0..1 + rng.below(6) {
+                path.push_str(rng.pick(WORDS));
+                path.push('/');
+            }
+            path.push_str(rng.pick(WORDS));
+            path.push('_');
+            path.push_str(&rng.below(1000).to_string());
+            path.push('.');
+            path.push_str(rng.pick(EXTENSIONS));
+            path
+        })
+        .collect()
+}
+
+fn main() {
+    let paths = corpus();
+    let start = Instant::now();
+    let choices: Vec<Choice> = paths.iter().cloned().map(Choice::new).collect();
+    println!(
+        "{:>12}: {} choices in {:?}",
+        "ingestion",
+        choices.len(),
+        start.elapsed()
+    );
+
+    let candidates: Vec<usize> = (0..choices.len()).collect();
+    println!(
+        "{:>12}  {:>8}  {:>12}  {:>12}",
+        "query", "matches", "baseline", "current"
+    );
+    for query in QUERIES {
+        let start = Instant::now();
+        let baseline_matches =
+            baseline::compute_matches(&paths, &candidates, query, CaseMode::Smart);
+        let baseline = start.elapsed();
+
+        let start = Instant::now();
+        let matches =
+            matching::compute_matches(&choices, &candidates, query, CaseMode::Smart, false);
+        let current = start.elapsed();
+        assert_eq!(
+            matches.len(),
+            baseline_matches.len(),
+            "matchers disagree on {query:?}"
+        );
+        println!(
+            "{:>12}  {:>8}  {:>12?}  {:>12?}",
+            query,
+            matches.len(),
+            baseline,
+            current
+        );
+    }
+}
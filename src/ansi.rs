@@ -1,10 +1,19 @@
+use crate::matching::Style;
 use crate::screen::Key;
 use crate::screen::Key::{
-    Backspace, Char, Control, Down, End, Enter, Home, PgDown, PgUp, Resize, ShiftTab, Tab, Up,
+    Alt, AltEnter, Backspace, Char, Click, Control, Delete, Down, End, Enter, Home, Left, PgDown,
+    PgUp, Resize, Right, RightClick, ShiftDown, ShiftPgDown, ShiftPgUp, ShiftTab, ShiftUp, Tab, Up,
+    WheelDown, WheelUp,
 };
 use crate::{ansi, logging};
+use std::ops::Range;
+use std::str;
 
+// Moving the cursor by zero is written as nothing, since terminals treat a count of 0 as 1.
 pub fn cursor_up(lines: u16, buf: &mut [u8; 16]) -> &[u8] {
+    if lines == 0 {
+        return &buf[..0];
+    }
     let mut itoa_buf = itoa::Buffer::new();
     let s = itoa_buf.format(lines);
     let len = 2 + s.len() + 1;
@@ -16,6 +25,9 @@ pub fn cursor_up(lines: u16, buf: &mut [u8; 16]) -> &[u8] {
 }
 
 pub fn cursor_right(lines: u16, buf: &mut [u8; 16]) -> &[u8] {
+    if lines == 0 {
+        return &buf[..0];
+    }
     let mut itoa_buf = itoa::Buffer::new();
     let s = itoa_buf.format(lines);
     let len = 2 + s.len() + 1;
@@ -42,14 +54,6 @@ pub const fn show_cursor() -> &'static [u8] {
     b"\x1b[?25h"
 }
 
-pub const fn inverse() -> &'static [u8] {
-    b"\x1b[7m"
-}
-
-pub const fn red() -> &'static [u8] {
-    b"\x1b[31m"
-}
-
 pub const fn reset() -> &'static [u8] {
     b"\x1b[0m"
 }
@@ -58,10 +62,18 @@ pub const fn blank_screen() -> &'static [u8] {
     b"\x1b[2J"
 }
 
+pub const fn cursor_home() -> &'static [u8] {
+    b"\x1b[H"
+}
+
 pub const fn clear_to_end_of_line() -> &'static [u8] {
     b"\x1b[K"
 }
 
+pub const fn clear_to_end_of_screen() -> &'static [u8] {
+    b"\x1b[J"
+}
+
 pub const fn begin_synchronized_update() -> &'static [u8] {
     b"\x1b[?2026h"
 }
@@ -70,6 +82,188 @@ pub const fn end_synchronized_update() -> &'static [u8] {
     b"\x1b[?2026l"
 }
 
+// Switches to the alternate screen, which starts out blank, and moves the cursor to its top.
+pub const fn enter_alternate_screen() -> &'static [u8] {
+    b"\x1b[?1049h\x1b[H"
+}
+
+// Switches back to the normal screen, as it was before `enter_alternate_screen`.
+pub const fn leave_alternate_screen() -> &'static [u8] {
+    b"\x1b[?1049l"
+}
+
+// Turns on reporting of mouse clicks and wheel movement, using the SGR encoding.
+pub const fn enable_mouse() -> &'static [u8] {
+    b"\x1b[?1000h\x1b[?1006h"
+}
+
+pub const fn disable_mouse() -> &'static [u8] {
+    b"\x1b[?1006l\x1b[?1000l"
+}
+
+// Asks the terminal to report the cursor position, which `find_cursor_position_report` parses.
+pub const fn request_cursor_position() -> &'static [u8] {
+    b"\x1b[6n"
+}
+
+// Finds the terminal's response to `request_cursor_position` in the given input, returning the
+// range of bytes that it occupies and the (0-based) row that the cursor is on.
+pub fn find_cursor_position_report(bytes: &[u8]) -> Option<(Range<usize>, u16)> {
+    (0..bytes.len()).find_map(|i| match parse_csi(&bytes[i..], b"")? {
+        (params, b'R', len) if params.len() == 2 => Some((i..i + len, params[0].saturating_sub(1))),
+        _ => None,
+    })
+}
+
+// Parses a control sequence with numeric parameters, such as `ESC [ < 0 ; 12 ; 3 M`, at the start
+// of `bytes`. Returns the parameters, the final byte, and the length of the sequence.
+fn parse_csi(bytes: &[u8], prefix: &[u8]) -> Option<(Vec<u16>, u8, usize)> {
+    let rest = bytes.strip_prefix(b"\x1b[")?.strip_prefix(prefix)?;
+    let end = rest
+        .iter()
+        .position(|b| !b.is_ascii_digit() && *b != b';')?;
+    let params = str::from_utf8(&rest[..end])
+        .ok()?
+        .split(';')
+        .map(|param| param.parse().ok())
+        .collect::<Option<Vec<u16>>>()?;
+    Some((params, rest[end], 2 + prefix.len() + end + 1))
+}
+
+// Returns the length of the complete CSI or SS3 sequence at the start of `bytes`, if there is one.
+fn unknown_sequence_len(bytes: &[u8]) -> Option<usize> {
+    match bytes {
+        // Parameter and intermediate bytes are in the range 0x20-0x3F, and the final byte is in the
+        // range 0x40-0x7E.
+        [27, b'[', rest @ ..] => {
+            let params = rest
+                .iter()
+                .take_while(|b| (0x20..=0x3f).contains(*b))
+                .count();
+            rest.get(params)
+                .filter(|b| (0x40..=0x7e).contains(*b))
+                .map(|_| 2 + params + 1)
+        }
+        [27, b'O', 0x40..=0x7e, ..] => Some(3),
+        _ => None,
+    }
+}
+
+// Parses an SGR-encoded mouse event at the start of `bytes`, returning the corresponding key (if
+// it's one that's handled) and the length of the sequence.
+fn parse_mouse_event(bytes: &[u8]) -> Option<(Option<Key>, usize)> {
+    let (params, final_byte, len) = parse_csi(bytes, b"<")?;
+    let &[button, col, row] = params.as_slice() else {
+        return None;
+    };
+    let (row, col) = (row.saturating_sub(1), col.saturating_sub(1));
+    // Modifier keys are ignored. Releases (`m`) and motion (32) aren't reported as keys.
+    let key = match (button & !0b11100, final_byte) {
+        (0, b'M') => Some(Click { row, col }),
+        (2, b'M') => Some(RightClick { row, col }),
+        (64, b'M') => Some(WheelUp { row, col }),
+        (65, b'M') => Some(WheelDown { row, col }),
+        (_, b'M' | b'm') => None,
+        _ => return None,
+    };
+    Some((key, len))
+}
+
+// A piece of text that may contain escape sequences, as split up by `tokenize`.
+#[derive(Debug, PartialEq)]
+pub enum Token<'a> {
+    Char(char),
+    // A Select Graphic Rendition sequence, which sets colors and text attributes.
+    Sgr(&'a str),
+    // Any other escape sequence.
+    Escape(&'a str),
+}
+
+pub fn tokenize(s: &str) -> impl Iterator<Item = Token<'_>> {
+    let mut rest = s;
+    std::iter::from_fn(move || {
+        let mut chars = rest.chars();
+        let c = chars.next()?;
+        if c != '\x1b' {
+            rest = chars.as_str();
+            return Some(Token::Char(c));
+        }
+        let (sequence, tail) = rest.split_at(escape_len(rest));
+        rest = tail;
+        if sequence.starts_with("\x1b[") && sequence.ends_with('m') {
+            Some(Token::Sgr(sequence))
+        } else {
+            Some(Token::Escape(sequence))
+        }
+    })
+}
+
+// Returns the length of the escape sequence at the start of `s`.
+fn escape_len(s: &str) -> usize {
+    let bytes = s.as_bytes();
+    match bytes.get(1) {
+        // Control sequences end with a byte in the range 0x40-0x7E.
+        Some(b'[') => bytes[2..]
+            .iter()
+            .position(|b| (0x40..=0x7e).contains(b))
+            .map_or(s.len(), |i| i + 3),
+        // Operating system commands end with BEL or ST (ESC \).
+        Some(b']') => (2..bytes.len())
+            .find_map(|i| match bytes[i] {
+                0x07 => Some(i + 1),
+                0x1b if bytes.get(i + 1) == Some(&b'\\') => Some(i + 2),
+                _ => None,
+            })
+            .unwrap_or(s.len()),
+        // Anything else is a single final character, possibly preceded by intermediate bytes in
+        // the range 0x20-0x2F (as in `ESC ( B`).
+        Some(_) => {
+            let intermediates = bytes[1..]
+                .iter()
+                .take_while(|b| (0x20..=0x2f).contains(*b))
+                .count();
+            let rest = &s[1 + intermediates..];
+            1 + intermediates + rest.chars().next().map_or(0, char::len_utf8)
+        }
+        None => 1,
+    }
+}
+
+// Removes every escape sequence from `s`, returning the remaining text along with the styles that
+// its SGR sequences applied to it.
+pub fn strip_escapes(s: &str) -> (String, Vec<Style>) {
+    let mut text = String::with_capacity(s.len());
+    let mut styles: Vec<Style> = Vec::new();
+    let mut current = String::new();
+    let mut len = 0;
+    for token in tokenize(s) {
+        match token {
+            Token::Char(c) => {
+                text.push(c);
+                len += 1;
+            }
+            Token::Sgr(sequence) => {
+                let params = &sequence[2..sequence.len() - 1];
+                if params.is_empty() || params == "0" || params.starts_with("0;") {
+                    current.clear();
+                }
+                if params != "0" && !params.is_empty() {
+                    current.push_str(sequence);
+                }
+                match styles.last_mut() {
+                    Some((idx, style)) if *idx == len => style.clone_from(&current),
+                    _ => styles.push((len, current.clone())),
+                }
+            }
+            Token::Escape(_) => {}
+        }
+    }
+    if styles.iter().all(|(_, style)| style.is_empty()) {
+        styles.clear();
+    }
+    (text, styles)
+}
+
 pub(crate) fn translate_bytes(bytes: &[u8]) -> Vec<Key> {
     const SEQUENCES: &[(&[u8], Option<Key>)] = &[
         (b"\x1B[5~", Some(PgUp)),
@@ -77,15 +271,20 @@ pub(crate) fn translate_bytes(bytes: &[u8]) -> Vec<Key> {
         (b"\x1B[H", Some(Home)),
         (b"\x1B[F", Some(End)),
         (b"\x1B[Z", Some(ShiftTab)),
+        (b"\x1B[5;2~", Some(ShiftPgUp)),
+        (b"\x1B[6;2~", Some(ShiftPgDown)),
+        (b"\x1B[1;2A", Some(ShiftUp)),
+        (b"\x1B[1;2B", Some(ShiftDown)),
         // Arrow keys
         (b"\x1B[A", Some(Up)),
         (b"\x1BOA", Some(Up)),
         (b"\x1B[B", Some(Down)),
         (b"\x1BOB", Some(Down)),
-        (b"\x1B[C", None),
-        (b"\x1BOC", None),
-        (b"\x1B[D", None),
-        (b"\x1BOD", None),
+        (b"\x1B[C", Some(Right)),
+        (b"\x1BOC", Some(Right)),
+        (b"\x1B[D", Some(Left)),
+        (b"\x1BOD", Some(Left)),
+        (b"\x1B[3~", Some(Delete)),
         // Focus lost
         (b"\x1B[O", None),
         // Focus gained
@@ -118,8 +317,32 @@ pub(crate) fn translate_bytes(bytes: &[u8]) -> Vec<Key> {
         }
 
         if !matched {
-            result.push(ansi::translate_char(bytes[i] as char));
-            i += 1;
+            if let Some((key, len)) = parse_mouse_event(current) {
+                result.extend(key);
+                i += len;
+                continue;
+            }
+            // Keys that aren't handled, such as F1 or Ctrl-Left, are ignored entirely, rather than
+            // being taken for Alt-[ or Alt-O followed by the rest of the sequence.
+            if let Some(len) = unknown_sequence_len(current) {
+                i += len;
+                continue;
+            }
+            // Terminals send Alt-modified keys prefixed with an escape.
+            match current {
+                [27, b'\r', ..] => {
+                    result.push(AltEnter);
+                    i += 2;
+                }
+                [27, c @ 0x20..=0x7e, ..] => {
+                    result.push(Alt(*c as char));
+                    i += 2;
+                }
+                _ => {
+                    result.push(ansi::translate_char(bytes[i] as char));
+                    i += 1;
+                }
+            }
         }
     }
 
@@ -149,7 +372,57 @@ pub(crate) fn translate_char(c: char) -> Key {
 #[cfg(test)]
 mod tests {
     use super::Key::*;
-    use super::translate_bytes;
+    use super::{Token, find_cursor_position_report, strip_escapes, tokenize, translate_bytes};
+
+    #[test]
+    fn tokenize_escape_sequences() {
+        let tokens: Vec<_> =
+            tokenize("a\x1b[1;31mb\x1b[2Kc\x1b]0;title\x07\x1b]8;;x\x1b\\\x1b(Bé\x1b").collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Char('a'),
+                Token::Sgr("\x1b[1;31m"),
+                Token::Char('b'),
+                Token::Escape("\x1b[2K"),
+                Token::Char('c'),
+                Token::Escape("\x1b]0;title\x07"),
+                Token::Escape("\x1b]8;;x\x1b\\"),
+                Token::Escape("\x1b(B"),
+                Token::Char('é'),
+                Token::Escape("\x1b"),
+            ]
+        );
+    }
+
+    #[test]
+    fn strip_escapes_records_styles() {
+        assert_eq!(strip_escapes("plain"), ("plain".to_string(), vec![]));
+        assert_eq!(
+            strip_escapes("\x1b[35msrc/main.rs\x1b[0m:\x1b[32m12\x1b[0m:fn main"),
+            (
+                "src/main.rs:12:fn main".to_string(),
+                vec![
+                    (0, "\x1b[35m".to_string()),
+                    (11, String::new()),
+                    (12, "\x1b[32m".to_string()),
+                    (14, String::new()),
+                ]
+            )
+        );
+        assert_eq!(
+            strip_escapes("\x1b[1m\x1b[31mab\x1b[4mc\x1b[0;32md\x1b[K"),
+            (
+                "abcd".to_string(),
+                vec![
+                    (0, "\x1b[1m\x1b[31m".to_string()),
+                    (2, "\x1b[1m\x1b[31m\x1b[4m".to_string()),
+                    (3, "\x1b[0;32m".to_string()),
+                ]
+            )
+        );
+        assert_eq!(strip_escapes("a\x1b[0mb"), ("ab".to_string(), vec![]));
+    }
 
     #[test]
     fn translate_bytes_escape() {
@@ -176,6 +449,66 @@ mod tests {
         assert_eq!(translate_bytes(b"ab\x1BOA"), vec![Char('a'), Char('b'), Up]);
     }
 
+    #[test]
+    fn translate_bytes_shifted_keys() {
+        assert_eq!(
+            translate_bytes(b"\x1B[1;2A\x1B[1;2B\x1B[5;2~\x1B[6;2~"),
+            vec![ShiftUp, ShiftDown, ShiftPgUp, ShiftPgDown]
+        );
+    }
+
+    #[test]
+    fn translate_bytes_alt_keys() {
+        assert_eq!(
+            translate_bytes(b"\x1Bx\x1B\r\x1B"),
+            vec![Alt('x'), AltEnter, Control('g')]
+        );
+    }
+
+    #[test]
+    fn translate_bytes_editing_keys() {
+        assert_eq!(
+            translate_bytes(b"\x1B[D\x1BOC\x1B[3~"),
+            vec![Left, Right, Delete]
+        );
+    }
+
+    #[test]
+    fn translate_bytes_unknown_sequences() {
+        // Ctrl-Left, F1, F5 and Alt-Up.
+        assert_eq!(
+            translate_bytes(b"\x1B[1;5Dx\x1BOPy\x1B[15~\x1B[1;3A"),
+            vec![Char('x'), Char('y')]
+        );
+        assert_eq!(translate_bytes(b"\x1B["), vec![Alt('[')]);
+        assert_eq!(translate_bytes(b"\x1BO"), vec![Alt('O')]);
+    }
+
+    #[test]
+    fn translate_bytes_mouse_events() {
+        assert_eq!(
+            translate_bytes(b"\x1B[<0;5;3M\x1B[<0;5;3mx\x1B[<2;1;1M\x1B[<64;10;20M\x1B[<69;1;2M"),
+            vec![
+                Click { row: 2, col: 4 },
+                Char('x'),
+                RightClick { row: 0, col: 0 },
+                WheelUp { row: 19, col: 9 },
+                WheelDown { row: 1, col: 0 },
+            ]
+        );
+        assert_eq!(translate_bytes(b"\x1B[<35;5;3M\x1B[<1;5;3M"), vec![]);
+    }
+
+    #[test]
+    fn cursor_position_reports() {
+        assert_eq!(
+            find_cursor_position_report(b"ab\x1B[12;40Rc"),
+            Some((2..10, 11))
+        );
+        assert_eq!(find_cursor_position_report(b"\x1B[A\x1B[12R"), None);
+        assert_eq!(find_cursor_position_report(b"\x1B[12;4"), None);
+    }
+
     #[test]
     fn translate_bytes_chars() {
         assert_eq!(translate_bytes(b"Ab"), vec![Char('A'), Char('b')]);
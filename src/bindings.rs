@@ -0,0 +1,224 @@
+use crate::screen::{self, Key, Key::*};
+use clap::ValueEnum;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+// The things a key can be bound to, named as they are with --bind and in the config file.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, ValueEnum)]
+pub enum Action {
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    First,
+    Last,
+    Toggle,
+    ToggleDown,
+    Accept,
+    Cancel,
+    BackwardDeleteChar,
+    BackwardKillWord,
+    ClearQuery,
+    BackwardChar,
+    ForwardChar,
+    BackwardWord,
+    ForwardWord,
+    BeginningOfLine,
+    EndOfLine,
+    DeleteChar,
+    KillWord,
+    KillLine,
+    Yank,
+    PreviousHistory,
+    NextHistory,
+    PreviewUp,
+    PreviewDown,
+    PreviewPageUp,
+    PreviewPageDown,
+    Ignore,
+}
+
+// Maps keys to the actions they perform. Keys without a binding insert themselves into the query if
+// they're printable characters, and are otherwise ignored.
+pub struct Bindings(Vec<(Key, Action)>);
+
+impl Default for Bindings {
+    fn default() -> Bindings {
+        use Action::*;
+        Bindings(vec![
+            (Backspace, BackwardDeleteChar),
+            (Control('h'), BackwardDeleteChar),
+            (Control('w'), BackwardKillWord),
+            (Control('u'), ClearQuery),
+            (Left, BackwardChar),
+            (Right, ForwardChar),
+            (Alt('b'), BackwardWord),
+            (Alt('f'), ForwardWord),
+            (Control('a'), BeginningOfLine),
+            (Control('e'), EndOfLine),
+            (Delete, DeleteChar),
+            (Alt('d'), KillWord),
+            (Control('k'), KillLine),
+            (Control('y'), Yank),
+            (Alt('p'), PreviousHistory),
+            (Alt('n'), NextHistory),
+            (Control('c'), Cancel),
+            (Control('g'), Cancel),
+            (Control('t'), ToggleDown),
+            (Control('p'), Action::Up),
+            (Key::Up, Action::Up),
+            (ShiftTab, Action::Up),
+            (Control('n'), Action::Down),
+            (Key::Down, Action::Down),
+            (Tab, Action::Down),
+            (Home, First),
+            (End, Last),
+            (Enter, Accept),
+            (Control('b'), PageUp),
+            (PgUp, PageUp),
+            (Control('f'), PageDown),
+            (PgDown, PageDown),
+            (ShiftUp, PreviewUp),
+            (ShiftDown, PreviewDown),
+            (ShiftPgUp, PreviewPageUp),
+            (ShiftPgDown, PreviewPageDown),
+        ])
+    }
+}
+
+impl Bindings {
+    // Returns the default bindings, overridden by those in the config file and then by the given
+    // ones from the command line.
+    pub fn load(overrides: &[(Key, Action)]) -> Result<Bindings, String> {
+        let mut bindings = Bindings::default();
+        if let Some(path) = config_path() {
+            match fs::read_to_string(&path) {
+                Ok(config) => {
+                    let config_bindings =
+                        parse_config(&config).map_err(|e| format!("{}:{}", path.display(), e))?;
+                    bindings.extend(&config_bindings);
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(format!("{}: {}", path.display(), e)),
+            }
+        }
+        bindings.extend(overrides);
+        Ok(bindings)
+    }
+
+    pub fn get(&self, key: Key) -> Option<Action> {
+        self.0
+            .iter()
+            .find(|(bound, _)| *bound == key)
+            .map(|&(_, action)| action)
+    }
+
+    fn extend(&mut self, bindings: &[(Key, Action)]) {
+        for &(key, action) in bindings {
+            self.0.retain(|(bound, _)| *bound != key);
+            self.0.push((key, action));
+        }
+    }
+}
+
+// Parses a binding such as `ctrl-j:down`. The key is separated from the action by the last colon,
+// so that the colon key itself can be bound.
+pub fn parse_binding(binding: &str) -> Result<(Key, Action), String> {
+    let (key, action) = binding
+        .rsplit_once(':')
+        .ok_or_else(|| format!("expected KEY:ACTION, found '{binding}'"))?;
+    let key = screen::parse_key(key)?;
+    let action =
+        Action::from_str(action, true).map_err(|_| format!("unknown action '{action}'"))?;
+    Ok((key, action))
+}
+
+// Parses the contents of a config file, which consists of lines such as `bind ctrl-j:down`. Blank
+// lines and lines starting with `#` are ignored. Errors are prefixed with the line number.
+fn parse_config(config: &str) -> Result<Vec<(Key, Action)>, String> {
+    let mut bindings = Vec::new();
+    for (i, line) in config.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let binding = match line.split_once(char::is_whitespace) {
+            Some(("bind", binding)) => parse_binding(binding.trim()),
+            _ => Err(format!("expected 'bind KEY:ACTION', found '{line}'")),
+        };
+        bindings.push(binding.map_err(|e| format!("{}: {}", i + 1, e))?);
+    }
+    Ok(bindings)
+}
+
+#[cfg(not(windows))]
+fn config_path() -> Option<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("heatseeker").join("config"))
+}
+
+#[cfg(windows)]
+fn config_path() -> Option<PathBuf> {
+    let config_dir = PathBuf::from(env::var_os("APPDATA")?);
+    Some(config_dir.join("heatseeker").join("config"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, Bindings, parse_binding, parse_config};
+    use crate::screen::Key::*;
+
+    #[test]
+    fn binding_syntax() {
+        assert_eq!(
+            parse_binding("ctrl-j:down"),
+            Ok((Control('j'), Action::Down))
+        );
+        assert_eq!(
+            parse_binding("alt-enter:toggle-down"),
+            Ok((AltEnter, Action::ToggleDown))
+        );
+        assert_eq!(parse_binding("::accept"), Ok((Char(':'), Action::Accept)));
+        assert!(parse_binding("ctrl-j").is_err());
+        assert!(parse_binding("ctrl-j:fly").is_err());
+        assert!(parse_binding("hyper-j:down").is_err());
+    }
+
+    #[test]
+    fn config_file_syntax() {
+        let config = "# Vim-style movement\nbind ctrl-j:down\n\n  bind   ctrl-k:up\n";
+        assert_eq!(
+            parse_config(config),
+            Ok(vec![
+                (Control('j'), Action::Down),
+                (Control('k'), Action::Up)
+            ])
+        );
+        assert_eq!(
+            parse_config("bind ctrl-j:down\nbind ctrl-k"),
+            Err("2: expected KEY:ACTION, found 'ctrl-k'".to_string())
+        );
+        assert!(parse_config("unbind ctrl-j").is_err());
+    }
+
+    #[test]
+    fn bindings_override_defaults() {
+        let mut bindings = Bindings::default();
+        assert_eq!(bindings.get(Control('n')), Some(Action::Down));
+        assert_eq!(bindings.get(Control('r')), None);
+        assert_eq!(bindings.get(Char('x')), None);
+
+        bindings.extend(&[
+            (Control('n'), Action::Ignore),
+            (Control('r'), Action::Toggle),
+        ]);
+        assert_eq!(bindings.get(Control('n')), Some(Action::Ignore));
+        assert_eq!(bindings.get(Control('r')), Some(Action::Toggle));
+        assert_eq!(bindings.get(Tab), Some(Action::Down));
+    }
+}
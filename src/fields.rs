@@ -0,0 +1,287 @@
+use crate::matching::{Choice, Style};
+use std::ops::Range;
+use std::str::FromStr;
+
+// Splits choices into fields, so that matching can be restricted to some of them (`--nth`) and so
+// that only some of them are displayed (`--with-nth`). By default, fields are separated by runs of
+// whitespace, as in awk. Each field includes the delimiter that follows it, except that a trailing
+// delimiter is stripped from the fields that are matched against or displayed.
+#[derive(Clone, Default)]
+pub struct Fields {
+    delimiter: Option<String>,
+    nth: Option<FieldSpec>,
+    with_nth: Option<FieldSpec>,
+}
+
+// A comma-separated list of 1-based field indices or ranges of indices, such as `1,3..5,-1`.
+// Negative indices count backwards from the last field, and either end of a range may be omitted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldSpec(Vec<FieldRange>);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FieldRange {
+    start: Option<isize>,
+    end: Option<isize>,
+}
+
+impl Fields {
+    pub fn new(
+        delimiter: Option<String>,
+        nth: Option<FieldSpec>,
+        with_nth: Option<FieldSpec>,
+    ) -> Fields {
+        Fields {
+            delimiter: delimiter.filter(|delimiter| !delimiter.is_empty()),
+            nth,
+            with_nth,
+        }
+    }
+
+    // Creates a choice from the given text, which is displayed with the given styles.
+    pub fn choice(&self, text: String, styles: Vec<Style>) -> Choice {
+        if self.nth.is_none() && self.with_nth.is_none() {
+            return Choice::new(text).with_styles(styles);
+        }
+        let (display, styles) = match &self.with_nth {
+            Some(spec) => {
+                let (display, offsets) = self.select(&text, spec);
+                (Some(display), remap_styles(&styles, &offsets))
+            }
+            None => (None, styles),
+        };
+        let key = self.key(display.as_deref().unwrap_or(&text));
+        Choice::with_fields(text, display, key).with_styles(styles)
+    }
+
+    // Returns the fields of `s` (which is the displayed text) that are matched against, along with
+    // the index within `s` of each of their characters, or None if all of `s` is matched against.
+    pub fn key(&self, s: &str) -> Option<(String, Vec<usize>)> {
+        let spec = self.nth.as_ref()?;
+        Some(self.select(s, spec))
+    }
+
+    fn select(&self, s: &str, spec: &FieldSpec) -> (String, Vec<usize>) {
+        let fields = self.split(s);
+        let mut selected = String::new();
+        let mut offsets = Vec::new();
+        let mut last_end = None;
+        for (i, field) in fields.iter().enumerate() {
+            if !spec.contains(i, fields.len()) {
+                continue;
+            }
+            let field_start = s[..field.start].chars().count();
+            selected.push_str(&s[field.clone()]);
+            offsets.extend(field_start..field_start + s[field.clone()].chars().count());
+            last_end = Some(field.end);
+        }
+
+        // Strip the delimiter from the end of the last field, if it has one.
+        if let Some(end) = last_end {
+            let trimmed = self.strip_delimiter(&s[..end]).len();
+            let excess = end - trimmed;
+            let excess_chars = s[trimmed..end].chars().count();
+            selected.truncate(selected.len() - excess);
+            offsets.truncate(offsets.len() - excess_chars);
+        }
+        (selected, offsets)
+    }
+
+    // Returns the byte ranges of the fields of `s`.
+    fn split(&self, s: &str) -> Vec<Range<usize>> {
+        let mut fields = Vec::new();
+        let mut start = 0;
+        match &self.delimiter {
+            Some(delimiter) => {
+                while let Some(idx) = s[start..].find(delimiter.as_str()) {
+                    let end = start + idx + delimiter.len();
+                    fields.push(start..end);
+                    start = end;
+                }
+            }
+            None => {
+                // A field is a run of non-whitespace characters, along with the whitespace that
+                // follows it. Leading whitespace is included in the first field.
+                let mut seen_token = false;
+                let mut in_whitespace = false;
+                for (idx, c) in s.char_indices() {
+                    if c.is_whitespace() {
+                        in_whitespace = seen_token;
+                    } else {
+                        if in_whitespace {
+                            fields.push(start..idx);
+                            start = idx;
+                            in_whitespace = false;
+                        }
+                        seen_token = true;
+                    }
+                }
+            }
+        }
+        if start < s.len() {
+            fields.push(start..s.len());
+        }
+        fields
+    }
+
+    fn strip_delimiter<'a>(&self, s: &'a str) -> &'a str {
+        match &self.delimiter {
+            Some(delimiter) => s.strip_suffix(delimiter.as_str()).unwrap_or(s),
+            None => s.trim_end(),
+        }
+    }
+}
+
+// Converts styles for a string into styles for a selection of its characters, where `offsets`
+// gives the index in the original string of each selected character.
+fn remap_styles(styles: &[Style], offsets: &[usize]) -> Vec<Style> {
+    let mut remapped = Vec::new();
+    let mut current = "";
+    for (idx, &offset) in offsets.iter().enumerate() {
+        let i = styles.partition_point(|(start, _)| *start <= offset);
+        let style = if i == 0 { "" } else { &styles[i - 1].1 };
+        if style != current {
+            remapped.push((idx, style.to_string()));
+            current = style;
+        }
+    }
+    remapped
+}
+
+impl FieldSpec {
+    // Returns true if the field with the given (0-based) index is included, out of `count` fields.
+    fn contains(&self, idx: usize, count: usize) -> bool {
+        let resolve = |i: isize| {
+            if i > 0 { i - 1 } else { count as isize + i }
+        };
+        let idx = idx as isize;
+        self.0.iter().any(|range| {
+            let start = range.start.map_or(0, resolve);
+            let end = range.end.map_or(count as isize - 1, resolve);
+            start <= idx && idx <= end
+        })
+    }
+}
+
+impl FromStr for FieldSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FieldSpec, String> {
+        let parse_index = |index: &str| -> Result<Option<isize>, String> {
+            if index.is_empty() {
+                return Ok(None);
+            }
+            match index.parse::<isize>() {
+                Ok(0) | Err(_) => Err(format!("invalid field index '{index}'")),
+                Ok(i) => Ok(Some(i)),
+            }
+        };
+        let ranges = s
+            .split(',')
+            .map(|range| match range.split_once("..") {
+                Some((start, end)) => Ok(FieldRange {
+                    start: parse_index(start)?,
+                    end: parse_index(end)?,
+                }),
+                None if range.is_empty() => Err("empty field index".to_string()),
+                None => {
+                    let index = parse_index(range)?;
+                    Ok(FieldRange {
+                        start: index,
+                        end: index,
+                    })
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(FieldSpec(ranges))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FieldSpec, Fields};
+
+    fn fields(delimiter: Option<&str>, nth: &str) -> Fields {
+        Fields::new(
+            delimiter.map(str::to_string),
+            Some(nth.parse().unwrap()),
+            None,
+        )
+    }
+
+    fn key(fields: &Fields, s: &str) -> String {
+        fields.key(s).unwrap().0
+    }
+
+    #[test]
+    fn field_spec_parsing() {
+        assert!("1".parse::<FieldSpec>().is_ok());
+        assert!("1,3..5,-1".parse::<FieldSpec>().is_ok());
+        assert!("2..".parse::<FieldSpec>().is_ok());
+        assert!("..-2".parse::<FieldSpec>().is_ok());
+        assert!("..".parse::<FieldSpec>().is_ok());
+        assert!("0".parse::<FieldSpec>().is_err());
+        assert!("x".parse::<FieldSpec>().is_err());
+        assert!("1,".parse::<FieldSpec>().is_err());
+        assert!("".parse::<FieldSpec>().is_err());
+    }
+
+    #[test]
+    fn whitespace_separated_fields() {
+        let s = "  PID  user   command --flag";
+        assert_eq!(key(&fields(None, "1"), s), "  PID");
+        assert_eq!(key(&fields(None, "2"), s), "user");
+        assert_eq!(key(&fields(None, "3.."), s), "command --flag");
+        assert_eq!(key(&fields(None, "-1"), s), "--flag");
+        assert_eq!(key(&fields(None, "..2"), s), "  PID  user");
+        assert_eq!(key(&fields(None, "1,3"), s), "  PID  command");
+        assert_eq!(key(&fields(None, "9"), s), "");
+    }
+
+    #[test]
+    fn delimited_fields() {
+        let s = "src/main.rs:12:fn main() {";
+        assert_eq!(key(&fields(Some(":"), "1"), s), "src/main.rs");
+        assert_eq!(key(&fields(Some(":"), "3.."), s), "fn main() {");
+        assert_eq!(key(&fields(Some(":"), "-2"), s), "12");
+        assert_eq!(key(&fields(Some(":"), "1,3"), s), "src/main.rs:fn main() {");
+        assert_eq!(key(&fields(Some("::"), "2"), "a::b::c"), "b");
+        assert_eq!(key(&fields(Some(":"), "2"), "a::b"), "");
+    }
+
+    #[test]
+    fn key_offsets_point_into_the_original() {
+        let (key, offsets) = fields(Some(","), "2,4").key("é,ab,c,dé").unwrap();
+        assert_eq!(key, "ab,dé");
+        assert_eq!(offsets, vec![2, 3, 4, 7, 8]);
+    }
+
+    #[test]
+    fn displayed_fields() {
+        let fields = Fields::new(None, None, Some("2..".parse().unwrap()));
+        let choice = fields.choice("abc123 Fix the bug".to_string(), Vec::new());
+        assert_eq!(choice.display(), "Fix the bug");
+        assert_eq!(choice.as_str(), "abc123 Fix the bug");
+        let choice = Fields::default().choice("abc123 Fix the bug".to_string(), Vec::new());
+        assert_eq!(choice.display(), "abc123 Fix the bug");
+    }
+
+    #[test]
+    fn displayed_fields_keep_their_styles() {
+        let fields = Fields::new(Some(":".into()), None, Some("1,3".parse().unwrap()));
+        // "src:12:text", with the file name in red and the line number in green.
+        let styles = vec![
+            (0, "red".into()),
+            (3, String::new()),
+            (4, "green".into()),
+            (6, String::new()),
+        ];
+        let choice = fields.choice("src:12:text".to_string(), styles.clone());
+        assert_eq!(choice.display(), "src:text");
+        assert_eq!(choice.styles(), [(0, "red".into()), (3, String::new())]);
+
+        let fields = Fields::new(Some(":".into()), None, Some("2..".parse().unwrap()));
+        let choice = fields.choice("src:12:text".to_string(), styles);
+        assert_eq!(choice.display(), "12:text");
+        assert_eq!(choice.styles(), [(0, "green".into()), (2, String::new())]);
+    }
+}
@@ -0,0 +1,120 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+// Only this many of the most recent queries are kept in the history file.
+const MAX_HISTORY: usize = 1000;
+
+// The queries accepted in previous runs, which can be recalled in place of the current query. The
+// history file has one query per line, oldest first.
+pub struct History {
+    path: PathBuf,
+    entries: Vec<String>,
+    // The index of the entry being shown, or `entries.len()` while the user's own query is shown.
+    position: usize,
+    // The query that was being edited before the user started moving through the history.
+    draft: String,
+}
+
+impl History {
+    // Reads the history file. A missing file is treated as an empty history.
+    pub fn load(path: PathBuf) -> io::Result<History> {
+        let entries: Vec<String> = match fs::read_to_string(&path) {
+            Ok(contents) => contents.lines().map(str::to_string).collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(History {
+            path,
+            position: entries.len(),
+            entries,
+            draft: String::new(),
+        })
+    }
+
+    // Returns the entry before the one being shown, if there is one. `query` is the current query,
+    // which is restored once the user moves forward past the most recent entry.
+    pub fn previous(&mut self, query: &str) -> Option<&str> {
+        if self.position == 0 {
+            return None;
+        }
+        if self.position == self.entries.len() {
+            self.draft = query.to_string();
+        }
+        self.position -= 1;
+        Some(&self.entries[self.position])
+    }
+
+    // Returns the entry after the one being shown, or the query that was being edited if the most
+    // recent entry is being shown.
+    pub fn next(&mut self) -> Option<&str> {
+        if self.position == self.entries.len() {
+            return None;
+        }
+        self.position += 1;
+        Some(self.entries.get(self.position).unwrap_or(&self.draft))
+    }
+
+    // Adds an accepted query to the history file, unless it's empty or the same as the most recent
+    // entry.
+    pub fn save(mut self, query: &str) -> io::Result<()> {
+        if query.is_empty()
+            || query.contains('\n')
+            || self.entries.last().is_some_and(|last| last == query)
+        {
+            return Ok(());
+        }
+        self.entries.push(query.to_string());
+        let start = self.entries.len().saturating_sub(MAX_HISTORY);
+        let mut contents = self.entries[start..].join("\n");
+        contents.push('\n');
+        fs::write(&self.path, contents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::History;
+    use std::path::PathBuf;
+
+    fn history(entries: &[&str]) -> History {
+        History {
+            path: PathBuf::new(),
+            entries: entries.iter().map(|s| s.to_string()).collect(),
+            position: entries.len(),
+            draft: String::new(),
+        }
+    }
+
+    #[test]
+    fn moving_through_the_history() {
+        let mut history = history(&["first", "second"]);
+        assert_eq!(history.next(), None);
+        assert_eq!(history.previous("draft"), Some("second"));
+        assert_eq!(history.previous("second"), Some("first"));
+        assert_eq!(history.previous("first"), None);
+        assert_eq!(history.next(), Some("second"));
+        assert_eq!(history.next(), Some("draft"));
+        assert_eq!(history.next(), None);
+    }
+
+    #[test]
+    fn saving_accepted_queries() {
+        let path = std::env::temp_dir().join(format!("hs-history-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        for query in ["first", "second", "second", "", "third"] {
+            History::load(path.clone()).unwrap().save(query).unwrap();
+        }
+        let mut history = History::load(path.clone()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(history.entries, ["first", "second", "third"]);
+        assert_eq!(history.previous(""), Some("third"));
+    }
+
+    #[test]
+    fn empty_history() {
+        let mut history = history(&[]);
+        assert_eq!(history.previous("draft"), None);
+        assert_eq!(history.next(), None);
+    }
+}
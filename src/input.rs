@@ -0,0 +1,166 @@
+use crate::ansi;
+use crate::fields::Fields;
+use crate::matching::Choice;
+use std::io::{self, BufRead, stdin};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+
+// Choices are read from stdin on a background thread and handed to the UI in batches, so that
+// the screen can be drawn (and the user can start typing) long before the input reaches EOF. Each
+// choice is also preprocessed for matching on that thread.
+pub struct ChoiceReader {
+    batches: Receiver<Vec<Choice>>,
+    reader: Option<JoinHandle<ReadErrors>>,
+    errors: ReadErrors,
+}
+
+// How the input is split up into choices, and how each choice is preprocessed.
+pub struct InputFormat {
+    // The byte that separates choices: a newline, or a NUL with `--read0`.
+    pub separator: u8,
+    // Whether choices contain ANSI escape sequences to be interpreted (see `--ansi`).
+    pub ansi: bool,
+    pub fields: Fields,
+}
+
+#[derive(Default)]
+struct ReadErrors {
+    first_error: Option<io::Error>,
+    suppressed: usize,
+}
+
+impl ReadErrors {
+    fn record(&mut self, err: io::Error) {
+        if self.first_error.is_some() {
+            self.suppressed += 1;
+        } else {
+            self.first_error = Some(err);
+        }
+    }
+}
+
+impl ChoiceReader {
+    pub fn spawn(format: InputFormat) -> ChoiceReader {
+        let (tx, rx) = mpsc::channel();
+        let reader = thread::spawn(move || read_batches(&tx, &format));
+        ChoiceReader {
+            batches: rx,
+            reader: Some(reader),
+            errors: ReadErrors::default(),
+        }
+    }
+
+    // Returns every choice that has arrived since the last call, without blocking.
+    pub fn try_read(&mut self) -> Vec<Choice> {
+        let mut choices = Vec::new();
+        loop {
+            match self.batches.try_recv() {
+                Ok(batch) => choices.extend(batch),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.join();
+                    break;
+                }
+            }
+        }
+        choices
+    }
+
+    // Blocks until stdin reaches EOF and returns every remaining choice.
+    pub fn read_all(&mut self) -> Vec<Choice> {
+        let mut choices = Vec::new();
+        for batch in self.batches.iter() {
+            choices.extend(batch);
+        }
+        self.join();
+        choices
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.reader.is_none()
+    }
+
+    pub fn report_errors(&self) {
+        if let Some(err) = &self.errors.first_error {
+            eprintln!(
+                "Warning: Failed to parse one or more lines (\"{}\"); {} additional error(s) suppressed",
+                err, self.errors.suppressed
+            );
+        }
+    }
+
+    fn join(&mut self) {
+        if let Some(reader) = self.reader.take() {
+            self.errors = reader.join().unwrap();
+        }
+    }
+}
+
+fn read_batches(tx: &Sender<Vec<Choice>>, format: &InputFormat) -> ReadErrors {
+    let separator = format.separator;
+    let stdin = stdin();
+    let mut stdin = stdin.lock();
+    let mut errors = ReadErrors::default();
+    let mut partial = Vec::new();
+
+    loop {
+        // Each batch is whatever a single read happened to return, which keeps latency low for
+        // slow producers without paying for a channel send per line on fast ones.
+        let available = match stdin.fill_buf() {
+            Ok([]) => break,
+            Ok(available) => available,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                errors.record(e);
+                break;
+            }
+        };
+        let len = available.len();
+        let mut batch = Vec::new();
+        for line in available.split_inclusive(|&b| b == separator) {
+            partial.extend_from_slice(line);
+            if partial.last() == Some(&separator) {
+                push_line(&mut batch, &mut partial, format, &mut errors);
+            }
+        }
+        stdin.consume(len);
+        if !batch.is_empty() && tx.send(batch).is_err() {
+            return errors;
+        }
+    }
+
+    if !partial.is_empty() {
+        let mut batch = Vec::new();
+        push_line(&mut batch, &mut partial, format, &mut errors);
+        let _ = tx.send(batch);
+    }
+    errors
+}
+
+fn push_line(
+    batch: &mut Vec<Choice>,
+    line: &mut Vec<u8>,
+    format: &InputFormat,
+    errors: &mut ReadErrors,
+) {
+    match String::from_utf8(std::mem::take(line)) {
+        Ok(mut s) => {
+            if format.separator == b'\n' {
+                crate::trim(&mut s);
+            } else if s.ends_with(format.separator as char) {
+                s.pop();
+            }
+            let choice = if format.ansi {
+                let (text, styles) = ansi::strip_escapes(&s);
+                format.fields.choice(text, styles)
+            } else {
+                format.fields.choice(s, Vec::new())
+            };
+            batch.push(choice);
+        }
+        Err(_) => errors.record(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "stream did not contain valid UTF-8",
+        )),
+    }
+}
@@ -0,0 +1,2 @@
+// The fuzzy matcher, which is also used by the benchmarks.
+pub mod matching;
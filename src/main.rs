@@ -1,24 +1,80 @@
 mod ansi;
+mod bindings;
+mod fields;
+mod history;
+mod input;
 mod logging;
-mod matching;
+mod matcher;
+mod preview;
 mod screen;
+mod theme;
 
 use self::SearchState::*;
+use bindings::{Action, Bindings};
 use clap::Parser;
+use fields::{FieldSpec, Fields};
+use heatseeker::matching;
+use history::History;
 use indexmap::IndexSet;
+use input::{ChoiceReader, InputFormat};
+use matcher::Matcher;
+use matching::{CaseMode, Choice, Matches, Style};
+use preview::{Preview, PreviewPosition};
 use screen::Key;
 use screen::Key::*;
-use screen::Screen;
+use screen::{Height, Screen};
+use std::borrow::Cow;
 use std::cmp::min;
 use std::env;
-use std::io::{BufRead, stdin};
-use unicode_width::UnicodeWidthStr;
+use std::mem;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use theme::{ColorSpec, TextStyle, Theme};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
 #[cfg(windows)]
 pub const NEWLINE: &str = "\r\n";
 #[cfg(not(windows))]
 pub const NEWLINE: &str = "\n";
 
+// The number of columns to leave blank at the right edge of the screen.
+#[cfg(windows)]
+const MARGIN: u16 = 1;
+#[cfg(not(windows))]
+const MARGIN: u16 = 0;
+
+// How often the screen is refreshed while choices are still being read from stdin, or while a
+// search is running in the background.
+const INPUT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// How long to wait for a search to finish before drawing the previous matches instead. Most
+// searches finish well within this, which avoids briefly showing out of date matches.
+const MATCH_WAIT: Duration = Duration::from_millis(10);
+
+// The exit status when a choice is selected, when there were no matches to select, and when the
+// search is canceled.
+const EXIT_SELECTED: u8 = 0;
+const EXIT_NO_MATCH: u8 = 1;
+const EXIT_CANCELED: u8 = 130;
+// The exit status when the config file or the history file can't be loaded.
+const EXIT_ERROR: u8 = 2;
+
+// Two clicks on the same match within this long of each other accept it.
+const DOUBLE_CLICK_INTERVAL: Duration = Duration::from_millis(500);
+
+// The number of lines taken up without --height: the prompt and 20 rows of matches.
+const DEFAULT_HEIGHT: u16 = 21;
+
+// Shown in place of the characters left out of choices that are too long to show in full.
+const ELLIPSIS: &str = "…";
+const ELLIPSIS_WIDTH: usize = 1;
+
+const SPINNER: &[char] = &['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+const SPINNER_FRAME: Duration = Duration::from_millis(80);
+
 mod built_info {
     include!(concat!(env!("OUT_DIR"), "/built.rs"));
 }
@@ -54,11 +110,195 @@ pub struct Args {
         help = "Use the entire screen in order to display as many choices as possible"
     )]
     pub full_screen: bool,
+    #[arg(
+        long = "height",
+        value_name = "HEIGHT",
+        value_parser = screen::parse_height,
+        help = "Number of lines to take up, including the prompt, or a percentage of the window (e.g. 40%) [default: 21]"
+    )]
+    pub height: Option<Height>,
+    #[arg(
+        long = "min-height",
+        value_name = "LINES",
+        default_value_t = 10,
+        help = "Minimum number of lines to take up when --height is a percentage"
+    )]
+    pub min_height: u16,
+    #[arg(
+        long = "layout",
+        value_name = "LAYOUT",
+        value_enum,
+        default_value_t = LayoutMode::Default,
+        help = "default (prompt above the matches) or reverse (prompt below, with the best match nearest it)"
+    )]
+    pub layout: LayoutMode,
+    #[arg(
+        long = "alt-screen",
+        help = "Draw on the terminal's alternate screen, leaving the shell's output untouched"
+    )]
+    pub alt_screen: bool,
     #[arg(
         long = "filter-only",
         help = "Just filter choices without ranking them"
     )]
     pub filter_only: bool,
+    #[arg(
+        long = "case",
+        value_name = "MODE",
+        value_parser = parse_case_mode,
+        default_value = "smart",
+        help = "Case sensitivity: smart (lowercase matches either case), ignore, or respect"
+    )]
+    pub case: CaseMode,
+    #[arg(
+        long = "preview",
+        value_name = "COMMAND",
+        help = "Show the output of COMMAND for the highlighted choice, with {} replaced by the choice"
+    )]
+    pub preview: Option<String>,
+    #[arg(
+        long = "preview-window",
+        value_name = "POSITION",
+        value_enum,
+        default_value_t = PreviewPosition::Right,
+        help = "Where to show the preview: right or bottom"
+    )]
+    pub preview_position: PreviewPosition,
+    #[arg(
+        short = 'd',
+        long = "delimiter",
+        value_name = "STRING",
+        help = "Field delimiter for --nth and --with-nth (default: runs of whitespace)"
+    )]
+    pub delimiter: Option<String>,
+    #[arg(
+        short = 'n',
+        long = "nth",
+        value_name = "FIELDS",
+        help = "Only match against the given fields, e.g. 1,3..5,-1"
+    )]
+    pub nth: Option<FieldSpec>,
+    #[arg(
+        long = "with-nth",
+        value_name = "FIELDS",
+        help = "Only display the given fields (the entire line is still printed when selected)"
+    )]
+    pub with_nth: Option<FieldSpec>,
+    #[arg(
+        long = "read0",
+        help = "Read input delimited by NUL characters instead of newlines"
+    )]
+    pub read0: bool,
+    #[arg(
+        long = "print0",
+        help = "Print output delimited by NUL characters instead of newlines"
+    )]
+    pub print0: bool,
+    #[arg(
+        long = "ansi",
+        help = "Display ANSI color escape sequences in the input, rather than matching against them"
+    )]
+    pub ansi: bool,
+    #[arg(
+        long = "expect",
+        value_name = "KEYS",
+        value_delimiter = ',',
+        value_parser = parse_expect_key,
+        help = "Comma-separated keys (e.g. ctrl-v,alt-enter) that also accept the selection; the key pressed is printed first"
+    )]
+    pub expect: Vec<(String, Key)>,
+    #[arg(
+        long = "print-query",
+        help = "Print the query as the first line of output, before the selections"
+    )]
+    pub print_query: bool,
+    #[arg(
+        long = "header-lines",
+        value_name = "N",
+        default_value_t = 0,
+        help = "Show the first N lines of input above the matches, instead of as choices"
+    )]
+    pub header_lines: usize,
+    #[arg(
+        long = "header",
+        value_name = "TEXT",
+        help = "Show TEXT above the matches"
+    )]
+    pub header: Option<String>,
+    #[arg(
+        long = "prompt",
+        value_name = "STRING",
+        default_value = "> ",
+        help = "The prompt shown before the query"
+    )]
+    pub prompt: String,
+    #[arg(
+        long = "pointer",
+        value_name = "STRING",
+        default_value = "",
+        help = "Shown to the left of the highlighted match"
+    )]
+    pub pointer: String,
+    #[arg(
+        long = "marker",
+        value_name = "STRING",
+        default_value = "✓",
+        help = "Shown to the left of selected choices"
+    )]
+    pub marker: String,
+    #[arg(
+        long = "keep-basename",
+        help = "When a choice is too long to show in full, keep the last component of its path visible"
+    )]
+    pub keep_basename: bool,
+    #[arg(
+        long = "bind",
+        value_name = "BINDINGS",
+        value_delimiter = ',',
+        value_parser = bindings::parse_binding,
+        help = "Comma-separated key bindings (e.g. ctrl-j:down,ctrl-k:up), overriding the defaults and the config file"
+    )]
+    pub bind: Vec<(Key, Action)>,
+    #[arg(
+        long = "history",
+        value_name = "FILE",
+        help = "Save accepted queries to FILE, and recall them with alt-p and alt-n"
+    )]
+    pub history: Option<PathBuf>,
+    #[arg(
+        long = "mouse",
+        help = "Capture the mouse, to click and scroll through the matches"
+    )]
+    pub mouse: bool,
+    #[arg(
+        long = "color",
+        value_name = "SPECS",
+        value_delimiter = ',',
+        value_parser = theme::parse_color_spec,
+        help = "Comma-separated color scheme (default, dark, light, bw) and element colors, e.g. hl:yellow,cursor-bg:236"
+    )]
+    pub color: Vec<ColorSpec>,
+    #[arg(
+        long = "no-color",
+        help = "Don't use colors (also enabled by setting NO_COLOR)"
+    )]
+    pub no_color: bool,
+}
+
+// The matcher doesn't depend on clap, so its case modes are parsed here.
+fn parse_case_mode(name: &str) -> Result<CaseMode, String> {
+    match name {
+        "smart" => Ok(CaseMode::Smart),
+        "ignore" => Ok(CaseMode::Ignore),
+        "respect" => Ok(CaseMode::Respect),
+        _ => Err(format!(
+            "unknown case mode '{name}' (expected smart, ignore, or respect)"
+        )),
+    }
+}
+
+fn parse_expect_key(name: &str) -> Result<(String, Key), String> {
+    screen::parse_key(name).map(|key| (name.to_string(), key))
 }
 
 #[derive(Debug, clap::Subcommand)]
@@ -70,7 +310,7 @@ pub enum Command {
     },
 }
 
-fn main() {
+fn main() -> ExitCode {
     unsafe {
         env::set_var("RUST_BACKTRACE", "1");
     }
@@ -94,7 +334,7 @@ fn main() {
                 built_info::TARGET
             );
         }
-        return;
+        return ExitCode::SUCCESS;
     }
 
     if let Some(Command::Shell { shell }) = &args.command {
@@ -106,84 +346,506 @@ fn main() {
             "bash" => print!("{}", BASH_CONFIG),
             _ => eprintln!("Error: Unsupported shell '{}'", shell),
         }
-        return;
+        return ExitCode::SUCCESS;
     }
 
-    let choices = read_choices();
+    let bindings = match Bindings::load(&args.bind) {
+        Ok(bindings) => bindings,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            return ExitCode::from(EXIT_ERROR);
+        }
+    };
+    let history = match args.history.clone().map(History::load).transpose() {
+        Ok(history) => history,
+        Err(e) => {
+            eprintln!("Error: Failed to read history file: {e}");
+            return ExitCode::from(EXIT_ERROR);
+        }
+    };
+
+    let mut reader = ChoiceReader::spawn(InputFormat {
+        separator: if args.read0 { b'\0' } else { b'\n' },
+        ansi: args.ansi,
+        fields: Fields::new(
+            args.delimiter.clone(),
+            args.nth.clone(),
+            args.with_nth.clone(),
+        ),
+    });
     let initial_search = args.initial_search.clone().unwrap_or_default();
-    let choices = choices.iter().map(|x| &x[..]).collect::<Vec<&str>>();
-    if args.use_first {
-        let matches = matching::compute_matches(&choices, &initial_search, args.filter_only);
-        println!("{}", matches.first().unwrap_or(&""));
+    let status = if args.use_first {
+        let choices = reader.read_all();
+        let candidates = (min(args.header_lines, choices.len())..choices.len()).collect::<Vec<_>>();
+        let mut matches = matching::compute_matches(
+            &choices,
+            &candidates,
+            &initial_search,
+            args.case,
+            args.filter_only,
+        );
+        matches.sort_to(1);
+        let first = matches.get(0).map_or("", |i| choices[i].as_str());
+        // No key was pressed, but the output should still start with a key line if one is expected.
+        print!("{}", output_header(&args, &initial_search, ""));
+        if !args.print0 {
+            println!("{first}");
+        } else if !first.is_empty() {
+            print!("{first}\0");
+        }
+        if matches.is_empty() {
+            EXIT_NO_MATCH
+        } else {
+            EXIT_SELECTED
+        }
     } else {
-        let desired_rows = if args.full_screen { 999 } else { 20 };
-        let selections = event_loop(desired_rows, &choices, &initial_search, args.filter_only);
+        let (selections, status) =
+            event_loop(&mut reader, &initial_search, &args, &bindings, history);
         print!("{selections}");
-    }
+        status
+    };
+    reader.report_errors();
+    ExitCode::from(status)
 }
 
 fn event_loop(
-    desired_rows: u16,
-    choices: &[&str],
+    reader: &mut ChoiceReader,
     initial_search: &str,
-    filter_only: bool,
-) -> String {
-    let mut search = Search::new(choices, initial_search.to_string(), filter_only);
-    let mut screen = screen::new(desired_rows);
+    args: &Args,
+    bindings: &Bindings,
+    history: Option<History>,
+) -> (String, u8) {
+    let mut search = Search::new(initial_search.to_string(), args.filter_only, args.case);
+    search.history = history;
+    search.header = args
+        .header
+        .iter()
+        .flat_map(|header| header.lines())
+        .map(|line| {
+            let (text, styles) = ansi::strip_escapes(line);
+            Choice::new(text).with_styles(styles)
+        })
+        .collect();
+    search.header_lines = args.header_lines;
+    search.keep_basename = args.keep_basename;
+    let height = match args.height {
+        _ if args.full_screen => Height::Percent {
+            percent: 100,
+            min_lines: 0,
+        },
+        Some(height) => height.with_min_lines(args.min_height),
+        None => Height::Lines(DEFAULT_HEIGHT),
+    };
+    let mut screen = screen::new(height, args.mouse, args.alt_screen);
+    let mut preview = args.preview.clone().map(Preview::spawn);
+    let preview_position = preview.as_ref().map(|_| args.preview_position);
+    let no_color = args.no_color || env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    let theme = Theme::new(&args.color, no_color);
+    let symbols = Symbols {
+        prompt: args.prompt.clone(),
+        pointer: args.pointer.clone(),
+        marker: args.marker.clone(),
+    };
+    let started = Instant::now();
 
     loop {
-        search.recompute_matches();
+        search.add_choices(reader.try_read());
+        let layout = Layout::new(
+            screen.as_ref(),
+            preview_position,
+            args.layout,
+            search.header.len(),
+        );
+        search.update_matches(Some(MATCH_WAIT));
+        search.rank_visible_matches(layout.choice_rows);
+        if let Some(preview) = &mut preview {
+            preview.show(search.highlighted_choice());
+            preview.update();
+        }
 
+        let spinner = search.is_matching().then(|| {
+            let frame = started.elapsed().as_millis() / SPINNER_FRAME.as_millis();
+            SPINNER[frame as usize % SPINNER.len()]
+        });
         match search.state {
-            InProgress => draw_screen(screen.as_mut(), &search),
+            InProgress => draw_screen(
+                screen.as_mut(),
+                &search,
+                preview.as_ref(),
+                &layout,
+                &theme,
+                &symbols,
+                spinner,
+            ),
             _ => break,
         }
 
-        let keys = screen.get_buffered_keys();
+        // While stdin is still being read, a search is running, or a preview is being generated,
+        // wake up periodically so that the results are displayed even if the user isn't typing.
+        let busy = !reader.is_finished()
+            || search.is_matching()
+            || preview.as_ref().is_some_and(Preview::is_loading);
+        let timeout = if !busy {
+            None
+        } else {
+            Some(INPUT_POLL_INTERVAL)
+        };
+        let keys = screen.get_buffered_keys(timeout);
         for key in &keys {
-            handle_key(&mut search, *key, &mut screen);
+            if search.state != InProgress {
+                break;
+            }
+            if let Some((name, _)) = args.expect.iter().find(|(_, expected)| expected == key) {
+                search.accept_with(name);
+            } else {
+                handle_key(
+                    &mut search,
+                    preview.as_mut(),
+                    *key,
+                    &mut screen,
+                    &layout,
+                    bindings,
+                );
+            }
         }
     }
 
     screen.blank_screen();
-    search.get_selections()
-}
-
-fn handle_key(search: &mut Search, key: Key, screen: &mut Box<dyn Screen>) {
-    let visible_choices = screen.visible_choices();
-    match key {
-        Char(x) => search.append(x),
-        Backspace | Control('h') => search.backspace(),
-        Control('w') => search.delete_word(),
-        Control('u') => search.clear_query(),
-        Control('r') => std::panic!("This is a test backtrace"),
-        Control('c' | 'g') => search.cancel(),
-        Control('t') => {
+    let selections = search.get_selections(args.print0);
+    let status = search.exit_status();
+    if search.state == Canceled {
+        return (selections, status);
+    }
+    if let Some(history) = search.history.take()
+        && let Err(e) = history.save(&search.query)
+    {
+        eprintln!("Warning: Failed to save history: {e}");
+    }
+    let key = search.accepted_with.as_deref().unwrap_or_default();
+    (
+        output_header(args, &search.query, key) + &selections,
+        status,
+    )
+}
+
+// Returns the lines that are printed before the selections: the query, if --print-query is given,
+// followed by the key that accepted the selection, if --expect is given.
+fn output_header(args: &Args, query: &str, key: &str) -> String {
+    let mut header = String::new();
+    if args.print_query {
+        header.push_str(query);
+        header.push_str(terminator(args.print0));
+    }
+    if !args.expect.is_empty() {
+        header.push_str(key);
+        header.push_str(terminator(args.print0));
+    }
+    header
+}
+
+// Returns the string that follows each line of output.
+fn terminator(print0: bool) -> &'static str {
+    if print0 { "\0" } else { NEWLINE }
+}
+
+fn handle_key(
+    search: &mut Search,
+    preview: Option<&mut Preview>,
+    key: Key,
+    screen: &mut Box<dyn Screen>,
+    layout: &Layout,
+    bindings: &Bindings,
+) {
+    let visible_choices = layout.choice_rows;
+    let preview_rows = layout.preview_rows();
+    let mut action = match (bindings.get(key), key) {
+        (Some(action), _) => action,
+        (None, Char(x)) => return search.insert(x),
+        (None, Resize) => return screen.resize(),
+        (None, Click { .. } | RightClick { .. } | WheelUp { .. } | WheelDown { .. }) => {
+            return handle_mouse(search, preview, key, screen.bottom_row(), layout);
+        }
+        (None, _) => return,
+    };
+    // The arrow and page keys move the highlight in the direction that they point, which is towards
+    // the worse matches in the reverse layout.
+    if layout.reverse {
+        action = match action {
+            Action::Up => Action::Down,
+            Action::Down => Action::Up,
+            Action::PageUp => Action::PageDown,
+            Action::PageDown => Action::PageUp,
+            action => action,
+        };
+    }
+    match action {
+        Action::Up => search.up(visible_choices),
+        Action::Down => search.down(visible_choices),
+        Action::PageUp => search.pgup(visible_choices),
+        Action::PageDown => search.pgdown(visible_choices),
+        Action::First => search.home(),
+        Action::Last => search.end(visible_choices),
+        Action::Toggle => search.toggle_selection(),
+        Action::ToggleDown => {
+            search.toggle_selection();
+            search.down(visible_choices);
+        }
+        Action::Accept => search.done(),
+        Action::Cancel => search.cancel(),
+        Action::BackwardDeleteChar => search.backspace(),
+        Action::BackwardKillWord => search.delete_word(),
+        Action::ClearQuery => search.clear_query(),
+        Action::BackwardChar => search.backward_char(),
+        Action::ForwardChar => search.forward_char(),
+        Action::BackwardWord => search.backward_word(),
+        Action::ForwardWord => search.forward_word(),
+        Action::BeginningOfLine => search.beginning_of_line(),
+        Action::EndOfLine => search.end_of_line(),
+        Action::DeleteChar => search.delete_char(),
+        Action::KillWord => search.kill_word(),
+        Action::KillLine => search.kill_line(),
+        Action::Yank => search.yank(),
+        Action::PreviousHistory => search.previous_history(),
+        Action::NextHistory => search.next_history(),
+        Action::PreviewUp => scroll_preview(preview, -1, preview_rows),
+        Action::PreviewDown => scroll_preview(preview, 1, preview_rows),
+        Action::PreviewPageUp => scroll_preview(preview, -(preview_rows as isize), preview_rows),
+        Action::PreviewPageDown => scroll_preview(preview, preview_rows as isize, preview_rows),
+        Action::Ignore => {}
+    }
+}
+
+// Clicking a match highlights it, and clicking it again accepts it. Right-clicking a match toggles
+// its selection. The wheel moves the highlight, or scrolls the preview if it's over the preview.
+fn handle_mouse(
+    search: &mut Search,
+    preview: Option<&mut Preview>,
+    key: Key,
+    bottom_row: Option<u16>,
+    layout: &Layout,
+) {
+    let (Click { row, col }
+    | RightClick { row, col }
+    | WheelUp { row, col }
+    | WheelDown { row, col }) = key
+    else {
+        return;
+    };
+    let Some(bottom_row) = bottom_row else {
+        return;
+    };
+    let visible_choices = layout.choice_rows;
+    let rank = search.scroll_offset + search.cursor_index;
+    // In the reverse layout, the worse matches are above the better ones.
+    let towards_worse = matches!(key, WheelDown { .. }) != layout.reverse;
+    match (key, layout.region(bottom_row, row, col)) {
+        (Click { .. }, Region::Choice(row)) => search.click(row as usize, Instant::now()),
+        (RightClick { .. }, Region::Choice(row)) if search.highlight(row as usize) => {
             search.toggle_selection();
+        }
+        (WheelUp { .. }, Region::Preview) => scroll_preview(preview, -1, layout.preview_rows()),
+        (WheelDown { .. }, Region::Preview) => scroll_preview(preview, 1, layout.preview_rows()),
+        (WheelUp { .. } | WheelDown { .. }, _) if !towards_worse && rank > 0 => {
+            search.up(visible_choices);
+        }
+        (WheelUp { .. } | WheelDown { .. }, _)
+            if towards_worse && rank + 1 < search.matches.len() =>
+        {
             search.down(visible_choices);
         }
-        Control('p') | Up | ShiftTab => search.up(visible_choices),
-        Control('n') | Down | Tab => search.down(visible_choices),
-        Home => search.home(),
-        End => search.end(visible_choices),
-        Enter => search.done(),
-        Control('b') | PgUp => search.pgup(visible_choices),
-        Control('f') | PgDown => search.pgdown(visible_choices),
-        Resize => screen.blank_entire_screen(),
-        Control(_) => {}
+        _ => {}
     }
 }
 
-struct Search<'a> {
-    choices: &'a [&'a str],
+fn scroll_preview(preview: Option<&mut Preview>, lines: isize, preview_rows: u16) {
+    if let Some(preview) = preview {
+        preview.scroll(lines, preview_rows);
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, clap::ValueEnum)]
+pub enum LayoutMode {
+    Default,
+    Reverse,
+}
+
+// The part of the layout that a position on the screen is in.
+#[derive(Debug, PartialEq)]
+enum Region {
+    // The row of matches with the given index.
+    Choice(u16),
+    Preview,
+    Other,
+}
+
+// What's drawn on one of the rows of the layout, not counting the preview on the right.
+#[derive(Debug, PartialEq)]
+enum Row {
+    // The line of the header with the given index.
+    Header(u16),
+    // The row of matches with the given index.
+    Choice(u16),
+    Border,
+    // The line of the preview with the given index.
+    Preview(u16),
+}
+
+// How the rows below the prompt line (or above it, in the reverse layout) are divided up between
+// the matches and the preview.
+struct Layout {
+    width: u16,
+    rows: u16,
+    // The header is drawn next to the prompt, between it and the matches.
+    header_rows: u16,
+    choice_rows: u16,
+    choice_width: u16,
+    preview: Option<PreviewPosition>,
+    reverse: bool,
+}
+
+impl Layout {
+    fn new(
+        screen: &dyn Screen,
+        preview: Option<PreviewPosition>,
+        mode: LayoutMode,
+        header_lines: usize,
+    ) -> Layout {
+        let (width, rows) = screen.winsize().unwrap();
+        let rows = min(screen.desired_rows(), rows.saturating_sub(1));
+        // At least one row is left for the matches.
+        let header_rows = min(header_lines, rows.saturating_sub(1) as usize) as u16;
+        let list_rows = rows - header_rows;
+        let (choice_rows, choice_width) = match preview {
+            None => (list_rows, width),
+            Some(PreviewPosition::Right) => (list_rows, width - width / 2),
+            Some(PreviewPosition::Bottom) => (list_rows.div_ceil(2), width),
+        };
+        Layout {
+            width,
+            rows,
+            header_rows,
+            choice_rows,
+            choice_width,
+            preview,
+            reverse: mode == LayoutMode::Reverse,
+        }
+    }
+
+    // Returns what's drawn on the given row, counting from the top row other than the prompt line.
+    // In the reverse layout, the matches are counted up from the prompt, but the header and the
+    // preview still read from top to bottom.
+    fn row(&self, row: u16) -> Row {
+        let from_prompt = if self.reverse {
+            self.rows - 1 - row
+        } else {
+            row
+        };
+        if from_prompt < self.header_rows {
+            return Row::Header(if self.reverse {
+                self.header_rows - 1 - from_prompt
+            } else {
+                from_prompt
+            });
+        }
+        let from_header = from_prompt - self.header_rows;
+        if from_header < self.choice_rows {
+            Row::Choice(from_header)
+        } else if from_header == self.choice_rows {
+            Row::Border
+        } else if self.reverse {
+            Row::Preview(row)
+        } else {
+            Row::Preview(from_header - self.choice_rows - 1)
+        }
+    }
+
+    // Returns the part of the layout at the given position in the window, where `bottom_row` is the
+    // window row that the last row of the layout is drawn on.
+    fn region(&self, bottom_row: u16, row: u16, col: u16) -> Region {
+        // Rows are counted from the top row other than the prompt line, which is at the bottom in
+        // the reverse layout.
+        let Some(row) = (row + self.rows + u16::from(self.reverse))
+            .checked_sub(bottom_row + 1)
+            .filter(|&row| row < self.rows)
+        else {
+            return Region::Other;
+        };
+        if self.preview == Some(PreviewPosition::Right) && col >= self.choice_width {
+            return Region::Preview;
+        }
+        match self.row(row) {
+            Row::Choice(row) => Region::Choice(row),
+            Row::Preview(_) => Region::Preview,
+            Row::Header(_) | Row::Border => Region::Other,
+        }
+    }
+
+    fn preview_rows(&self) -> u16 {
+        match self.preview {
+            None => 0,
+            Some(PreviewPosition::Right) => self.rows,
+            // One row is taken up by the border above the preview.
+            Some(PreviewPosition::Bottom) => self
+                .rows
+                .saturating_sub(self.header_rows + self.choice_rows + 1),
+        }
+    }
+}
+
+struct Search {
+    choices: Arc<Vec<Choice>>,
+    // Choices that have been read but not yet appended to `choices`, which can't be modified while
+    // the matcher is using it.
+    pending_choices: Vec<Choice>,
     query: String,
-    matches: Vec<&'a str>,
+    // The position in the query at which it's being edited, as a byte offset.
+    query_cursor: usize,
+    // The text most recently deleted by a kill command, which can be inserted again by yanking.
+    kill_buffer: String,
+    // Previously accepted queries, if --history is given.
+    history: Option<History>,
+    // The lines shown above the matches: the --header text, followed by the --header-lines lines
+    // of input.
+    header: Vec<Choice>,
+    // How many more lines of input are still to be taken for the header.
+    header_lines: usize,
+    // Whether long choices are shortened so that the last component of the path stays visible.
+    keep_basename: bool,
+    matches: Matches,
+    // The query that `matches` were computed for, and the number of choices that had been read.
+    matches_query: String,
+    matches_choice_count: usize,
+    match_cache: Vec<CachedMatches>,
+    matcher: Matcher,
+    // The search that the matcher is currently running for the current query, if any.
+    running: Option<RunningSearch>,
     stale: bool,
     scroll_offset: usize,
     cursor_index: usize,
     state: SearchState,
+    // The name of the --expect key that accepted the selection, if any.
+    accepted_with: Option<String>,
     selections: IndexSet<String>,
+    // When the last click was, and the rank of the match that it highlighted.
+    last_click: Option<(Instant, usize)>,
     filter_only: bool,
+    case: CaseMode,
+}
+
+// The matches for an earlier query. The cache is kept as a stack of results for successively
+// longer prefixes of the current query, so that deleting characters can restore earlier results
+// without recomputing them, and appending characters only has to narrow down the last results.
+// Matches are moved into the cache once they're no longer shown, so they're never copied.
+struct CachedMatches {
+    query: String,
+    matches: Matches,
+    // The number of choices that had been read when the matches were computed.
+    choice_count: usize,
+}
+
+struct RunningSearch {
+    query: String,
+    choice_count: usize,
 }
 
 #[derive(PartialEq, Eq)]
@@ -193,23 +855,57 @@ enum SearchState {
     Canceled,
 }
 
-impl<'a> Search<'a> {
-    fn new(choices: &'a [&'a str], initial_search: String, filter_only: bool) -> Search<'a> {
-        let matches = choices.to_vec();
+impl Search {
+    fn new(initial_search: String, filter_only: bool, case: CaseMode) -> Search {
         Search {
-            choices,
-            query: initial_search,
-            matches,
+            choices: Arc::new(Vec::new()),
+            pending_choices: Vec::new(),
+            query_cursor: initial_search.len(),
+            query: initial_search.clone(),
+            kill_buffer: String::new(),
+            history: None,
+            matches: Matches::default(),
+            // There are no matches for any query until choices have been read.
+            matches_query: initial_search,
+            matches_choice_count: 0,
+            match_cache: Vec::new(),
+            matcher: Matcher::spawn(),
+            running: None,
             stale: true,
             scroll_offset: 0,
             cursor_index: 0,
             state: InProgress,
+            accepted_with: None,
+            last_click: None,
+            header: Vec::new(),
+            header_lines: 0,
+            keep_basename: false,
             selections: IndexSet::new(),
             filter_only,
+            case,
+        }
+    }
+
+    fn add_choices(&mut self, mut choices: Vec<Choice>) {
+        let header_lines = min(self.header_lines, choices.len());
+        self.header.extend(choices.drain(..header_lines));
+        self.header_lines -= header_lines;
+        if choices.is_empty() {
+            return;
         }
+        self.pending_choices.extend(choices);
+        self.stale = true;
     }
 
+    fn choice_count(&self) -> usize {
+        self.choices.len() + self.pending_choices.len()
+    }
+
+    // The highlight can't move when no matches fit in the window, as with `--height 1`.
     fn up(&mut self, visible_choices: u16) {
+        if self.matches.is_empty() || visible_choices == 0 {
+            return;
+        }
         let match_count = self.matches.len();
         let limit = min(visible_choices as usize - 1, match_count - 1);
         let should_wrap = self.scroll_offset == 0;
@@ -230,6 +926,9 @@ impl<'a> Search<'a> {
     }
 
     fn down(&mut self, visible_choices: u16) {
+        if self.matches.is_empty() || visible_choices == 0 {
+            return;
+        }
         let match_count = self.matches.len();
         let limit = min(visible_choices as usize - 1, match_count - 1);
         let should_wrap = self.cursor_index + self.scroll_offset == match_count - 1;
@@ -266,58 +965,312 @@ impl<'a> Search<'a> {
 
     fn pgdown(&mut self, visible_choices: u16) {
         for _ in 0..visible_choices {
-            if self.scroll_offset + self.cursor_index == self.matches.len() - 1 {
+            if self.scroll_offset + self.cursor_index + 1 >= self.matches.len() {
                 return;
             }
             self.down(visible_choices);
         }
     }
 
+    fn insert(&mut self, c: char) {
+        let cursor = self.query_cursor;
+        self.replace_query(cursor..cursor, c.encode_utf8(&mut [0; 4]));
+    }
+
     fn backspace(&mut self) {
-        self.query.pop();
-        self.stale = true;
-        self.cursor_index = 0;
-        self.scroll_offset = 0;
-        self.matches = self.choices.to_vec();
+        let cursor = self.query_cursor;
+        let start = self.query[..cursor]
+            .chars()
+            .next_back()
+            .map_or(cursor, |c| cursor - c.len_utf8());
+        self.replace_query(start..cursor, "");
+    }
+
+    fn delete_char(&mut self) {
+        let cursor = self.query_cursor;
+        let end = self.query[cursor..]
+            .chars()
+            .next()
+            .map_or(cursor, |c| cursor + c.len_utf8());
+        self.replace_query(cursor..end, "");
     }
 
     fn delete_word(&mut self) {
-        self.stale = true;
-        delete_last_word(&mut self.query);
-        self.matches = self.choices.to_vec();
+        let mut before_cursor = self.query[..self.query_cursor].to_string();
+        delete_last_word(&mut before_cursor);
+        self.kill(before_cursor.len()..self.query_cursor);
     }
 
-    fn append(&mut self, c: char) {
-        self.query.push(c);
-        self.stale = true;
-        self.cursor_index = 0;
-        self.scroll_offset = 0;
+    fn kill_word(&mut self) {
+        let end = next_word_end(&self.query, self.query_cursor);
+        self.kill(self.query_cursor..end);
+    }
+
+    fn kill_line(&mut self) {
+        self.kill(self.query_cursor..self.query.len());
     }
 
     fn clear_query(&mut self) {
-        self.query.clear();
+        self.kill(0..self.query.len());
+    }
+
+    fn yank(&mut self) {
+        let cursor = self.query_cursor;
+        let text = self.kill_buffer.clone();
+        self.replace_query(cursor..cursor, &text);
+    }
+
+    fn previous_history(&mut self) {
+        let query = &self.query;
+        if let Some(entry) = self.history.as_mut().and_then(|h| h.previous(query)) {
+            let entry = entry.to_string();
+            self.replace_query(0..self.query.len(), &entry);
+        }
+    }
+
+    fn next_history(&mut self) {
+        if let Some(entry) = self.history.as_mut().and_then(History::next) {
+            let entry = entry.to_string();
+            self.replace_query(0..self.query.len(), &entry);
+        }
+    }
+
+    fn backward_char(&mut self) {
+        if let Some(c) = self.query[..self.query_cursor].chars().next_back() {
+            self.query_cursor -= c.len_utf8();
+        }
+    }
+
+    fn forward_char(&mut self) {
+        if let Some(c) = self.query[self.query_cursor..].chars().next() {
+            self.query_cursor += c.len_utf8();
+        }
+    }
+
+    fn backward_word(&mut self) {
+        self.query_cursor = previous_word_start(&self.query, self.query_cursor);
+    }
+
+    fn forward_word(&mut self) {
+        self.query_cursor = next_word_end(&self.query, self.query_cursor);
+    }
+
+    fn beginning_of_line(&mut self) {
+        self.query_cursor = 0;
+    }
+
+    fn end_of_line(&mut self) {
+        self.query_cursor = self.query.len();
+    }
+
+    // Deletes the given byte range of the query, saving it so that it can be yanked.
+    fn kill(&mut self, range: Range<usize>) {
+        if !range.is_empty() {
+            self.kill_buffer = self.query[range.clone()].to_string();
+        }
+        self.replace_query(range, "");
+    }
+
+    // Replaces the given byte range of the query, leaving the cursor after the replacement.
+    fn replace_query(&mut self, range: Range<usize>, replacement: &str) {
+        self.query_cursor = range.start + replacement.len();
+        self.query.replace_range(range, replacement);
+        self.stale = true;
         self.cursor_index = 0;
         self.scroll_offset = 0;
-        self.matches = self.choices.to_vec();
     }
 
+    // Blocks until the matches are up to date.
     fn recompute_matches(&mut self) {
-        if self.stale {
-            self.matches = matching::compute_matches(&self.matches, &self.query, self.filter_only);
-            self.stale = false;
+        self.update_matches(None);
+    }
+
+    // Brings the matches up to date, waiting at most `timeout` (or indefinitely, if it's None) for
+    // the matcher. If the search takes longer than that, the previous matches are left in place
+    // while it continues in the background.
+    fn update_matches(&mut self, timeout: Option<Duration>) {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            self.start_matching();
+            if !self.matcher.is_busy() {
+                return;
+            }
+            let remaining =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            if let Some(matches) = self.matcher.wait(remaining) {
+                self.finish_matching(matches);
+            } else if self.matcher.is_busy() {
+                return;
+            }
+        }
+    }
+
+    fn start_matching(&mut self) {
+        if !self.stale {
+            return;
+        }
+        if let Some(running) = &self.running {
+            // Choices that arrive during a search are matched once it finishes, rather than
+            // restarting it; otherwise a steady stream of input could keep it from ever finishing.
+            if running.query == self.query {
+                return;
+            }
+            self.matcher.cancel();
+            self.running = None;
+        }
+        if let Some(choices) = Arc::get_mut(&mut self.choices) {
+            choices.append(&mut self.pending_choices);
+        }
+        self.stale = !self.pending_choices.is_empty();
+
+        while self
+            .match_cache
+            .last()
+            .is_some_and(|cached| !self.query.starts_with(&cached.query))
+        {
+            self.match_cache.pop();
+        }
+
+        let choice_count = self.choices.len();
+        if self.matches_query == self.query && self.matches_choice_count == choice_count {
+            return;
+        }
+        let query = &self.query;
+        if let Some(cached) = self
+            .match_cache
+            .pop_if(|cached| cached.query == *query && cached.choice_count == choice_count)
+        {
+            self.show_matches(cached);
+            return;
+        }
+
+        // Start from the most recent results that are guaranteed to contain every match for the
+        // current query, plus any choices that have been read since they were computed.
+        let shown = (
+            &self.matches_query,
+            &self.matches,
+            self.matches_choice_count,
+        );
+        let base = self
+            .match_cache
+            .iter()
+            .map(|cached| (&cached.query, &cached.matches, cached.choice_count))
+            .chain([shown])
+            .rfind(|(query, _, _)| matching::narrows(query, &self.query, self.case));
+        let mut candidates = Vec::new();
+        let mut first_new = 0;
+        if let Some((_, matches, choice_count)) = base {
+            candidates.extend(matches.choices());
+            first_new = choice_count;
         }
+        candidates.extend(first_new..self.choices.len());
+        self.matcher.start(
+            self.choices.clone(),
+            candidates,
+            &self.query,
+            self.case,
+            self.filter_only,
+        );
+        self.running = Some(RunningSearch {
+            query: self.query.clone(),
+            choice_count: self.choices.len(),
+        });
+    }
+
+    fn finish_matching(&mut self, matches: Matches) {
+        let running = self.running.take().unwrap();
+        self.show_matches(CachedMatches {
+            query: running.query,
+            matches,
+            choice_count: running.choice_count,
+        });
+    }
+
+    // Replaces the matches being shown, caching the previous ones if they can still be used to
+    // narrow down the matches for the current query.
+    fn show_matches(&mut self, matches: CachedMatches) {
+        let previous = CachedMatches {
+            query: mem::replace(&mut self.matches_query, matches.query),
+            matches: mem::replace(&mut self.matches, matches.matches),
+            choice_count: mem::replace(&mut self.matches_choice_count, matches.choice_count),
+        };
+        if self.query.starts_with(&previous.query) {
+            if self
+                .match_cache
+                .last()
+                .is_some_and(|cached| cached.query == previous.query)
+            {
+                self.match_cache.pop();
+            }
+            self.match_cache.push(previous);
+        }
+
+        // The highlight may have been moved past the end of the new matches while the previous
+        // ones were shown.
+        let last = self.matches.len().saturating_sub(1);
+        if self.scroll_offset + self.cursor_index > last {
+            self.cursor_index = min(self.cursor_index, last);
+            self.scroll_offset = last - self.cursor_index;
+        }
+    }
+
+    fn is_matching(&self) -> bool {
+        self.running.is_some()
+    }
+
+    // Makes sure that every match that can currently be displayed has been ranked, along with a
+    // page of lookahead so that scrolling down doesn't immediately require more sorting.
+    fn rank_visible_matches(&mut self, visible_choices: u16) {
+        self.matches
+            .sort_to(self.scroll_offset + 2 * visible_choices as usize);
+    }
+
+    // Returns the highlighted choice, which must already have been ranked.
+    fn highlighted_choice(&self) -> Option<&str> {
+        let rank = self.scroll_offset + self.cursor_index;
+        self.matches.get(rank).map(|i| self.choices[i].as_str())
     }
 
     fn current_selection(&mut self) -> String {
         self.recompute_matches();
-        (*self
-            .matches
-            .get(self.scroll_offset + self.cursor_index)
-            .unwrap_or(&""))
-        .to_string()
+        let rank = self.scroll_offset + self.cursor_index;
+        self.matches.sort_to(rank + 1);
+        self.matches
+            .get(rank)
+            .map_or("", |i| self.choices[i].as_str())
+            .to_string()
+    }
+
+    // Highlights the match on the given row, returning false if there's no match there.
+    fn highlight(&mut self, row: usize) -> bool {
+        if self.scroll_offset + row >= self.matches.len() {
+            return false;
+        }
+        self.cursor_index = row;
+        true
+    }
+
+    // Highlights the clicked match, or accepts it if this is the second click on it in quick
+    // succession.
+    fn click(&mut self, row: usize, now: Instant) {
+        if !self.highlight(row) {
+            self.last_click = None;
+            return;
+        }
+        let rank = self.scroll_offset + row;
+        let double_click = self.last_click.is_some_and(|(time, clicked)| {
+            clicked == rank && now.duration_since(time) < DOUBLE_CLICK_INTERVAL
+        });
+        if double_click {
+            self.done();
+        }
+        self.last_click = Some((now, rank));
     }
 
     fn toggle_selection(&mut self) {
+        if self.matches.is_empty() {
+            return;
+        }
         let selection = self.current_selection();
         if self.selections.contains(&selection) {
             self.selections.shift_remove(&selection);
@@ -326,20 +1279,38 @@ impl<'a> Search<'a> {
         }
     }
 
-    fn get_selections(&mut self) -> String {
+    // Returns the selected choices, each followed by a newline. If nothing has been selected with
+    // ^T, the highlighted choice is returned on its own, without a newline. When `print0` is set,
+    // every choice is followed by a NUL instead.
+    fn get_selections(&mut self, print0: bool) -> String {
+        let terminator = terminator(print0);
         let mut ret = String::new();
         if self.state != Canceled {
             for selection in &self.selections {
                 ret.push_str(selection);
-                ret.push_str(NEWLINE);
+                ret.push_str(terminator);
             }
             if ret.is_empty() {
-                return self.current_selection();
+                ret = self.current_selection();
+                if print0 && !ret.is_empty() {
+                    ret.push('\0');
+                }
             }
         }
         ret
     }
 
+    // Returns the status to exit with once the search is over. The matches must be up to date.
+    fn exit_status(&self) -> u8 {
+        if self.state == Canceled {
+            EXIT_CANCELED
+        } else if self.selections.is_empty() && self.matches.is_empty() {
+            EXIT_NO_MATCH
+        } else {
+            EXIT_SELECTED
+        }
+    }
+
     fn cancel(&mut self) {
         self.state = Canceled;
     }
@@ -347,155 +1318,329 @@ impl<'a> Search<'a> {
     fn done(&mut self) {
         self.state = Done;
     }
+
+    fn accept_with(&mut self, key_name: &str) {
+        self.accepted_with = Some(key_name.to_string());
+        self.done();
+    }
 }
 
-fn draw_screen(screen: &mut dyn Screen, search: &Search) {
-    let (width, rows) = screen.winsize().unwrap();
-    let visible_choices = min(screen.desired_rows(), rows.saturating_sub(1));
+// The text drawn around the query and the matches.
+struct Symbols {
+    prompt: String,
+    // Drawn to the left of the highlighted match, with blank space in its place next to the others.
+    pointer: String,
+    // Drawn to the left of selected choices, after the pointer.
+    marker: String,
+}
 
+impl Symbols {
+    // Returns the width of the gutter to the left of the matches, which holds the pointer and the
+    // marker, followed by a space.
+    fn gutter_width(&self) -> u16 {
+        let width = UnicodeWidthStr::width(self.pointer.as_str())
+            + UnicodeWidthStr::width(self.marker.as_str());
+        if width == 0 { 0 } else { width as u16 + 1 }
+    }
+}
+
+fn draw_screen(
+    screen: &mut dyn Screen,
+    search: &Search,
+    preview: Option<&Preview>,
+    layout: &Layout,
+    theme: &Theme,
+    symbols: &Symbols,
+    spinner: Option<char>,
+) {
     screen.hide_cursor();
     screen.write_bytes(ansi::begin_synchronized_update());
-    screen.reset_cursor_with_rows(visible_choices);
-    screen.write(&format!(
-        "> {} ({}/{} choices)",
-        search.query,
-        search.matches.len(),
-        search.choices.len()
-    ));
-    screen.write_bytes(ansi::clear_to_end_of_line());
-    screen.write(NEWLINE);
-
-    print_matches(
-        screen,
-        &search.matches,
-        &search.query,
-        search.scroll_offset,
-        search.cursor_index,
-        width,
-        visible_choices,
-        &search.selections,
-    );
+    screen.reset_cursor_with_rows(layout.rows);
+    if !layout.reverse {
+        draw_prompt(screen, search, theme, symbols, spinner);
+        screen.write(NEWLINE);
+    }
+
+    let preview_lines = preview.map_or(&[][..], |preview| {
+        preview.visible_lines(layout.preview_rows())
+    });
+    for row in 0..layout.rows {
+        match (layout.row(row), layout.preview) {
+            (Row::Header(line), _) => {
+                let line = &search.header[line as usize];
+                print_header_row(screen, theme, symbols, layout.choice_width, line);
+            }
+            (Row::Choice(index), _) => {
+                let index = index as usize;
+                print_matches_row(screen, search, theme, symbols, layout.choice_width, index);
+            }
+            (Row::Border, Some(PreviewPosition::Bottom)) => {
+                let width = layout.width.saturating_sub(MARGIN);
+                screen.write_styled(&theme.border, &"─".repeat(width as usize));
+            }
+            (Row::Preview(line), Some(PreviewPosition::Bottom)) => {
+                print_preview_line(
+                    screen,
+                    preview_lines.get(line as usize),
+                    layout.width.saturating_sub(MARGIN),
+                );
+            }
+            _ => {}
+        }
+        screen.write_bytes(ansi::clear_to_end_of_line());
+        if layout.preview == Some(PreviewPosition::Right) {
+            let mut buf = [0u8; 16];
+            screen.write("\r");
+            screen.write_bytes(ansi::cursor_right(layout.choice_width, &mut buf));
+            screen.write_styled(&theme.border, "│");
+            screen.write(" ");
+            let width = (layout.width - layout.choice_width).saturating_sub(2 + MARGIN);
+            print_preview_line(screen, preview_lines.get(row as usize), width);
+        }
+        if row + 1 < layout.rows || layout.reverse {
+            screen.write(NEWLINE);
+        }
+    }
+    if layout.reverse {
+        draw_prompt(screen, search, theme, symbols, spinner);
+    }
 
-    let query_str: &str = &search.query;
+    let before_cursor = &search.query[..search.query_cursor];
+    let prompt_width = UnicodeWidthStr::width(symbols.prompt.as_str());
     screen.move_cursor_to_prompt_line_with_rows(
-        2 + UnicodeWidthStr::width(query_str) as u16,
-        visible_choices,
+        (prompt_width + UnicodeWidthStr::width(before_cursor)) as u16,
+        if layout.reverse { 0 } else { layout.rows },
     );
     screen.write_bytes(ansi::end_synchronized_update());
     screen.show_cursor();
 }
 
-fn print_matches(
+fn draw_prompt(
     screen: &mut dyn Screen,
-    matches: &[&str],
-    query: &str,
-    scroll_offset: usize,
-    cursor_index: usize,
+    search: &Search,
+    theme: &Theme,
+    symbols: &Symbols,
+    spinner: Option<char>,
+) {
+    screen.write_styled(&theme.prompt, &symbols.prompt);
+    screen.write(&format!("{} ", search.query));
+    screen.write_styled(
+        &theme.info,
+        &format!(
+            "({}/{} choices)",
+            search.matches.len(),
+            search.choice_count()
+        ),
+    );
+    if let Some(spinner) = spinner {
+        screen.write(" ");
+        screen.write_styled(&theme.spinner, &spinner.to_string());
+    }
+    screen.write_bytes(ansi::clear_to_end_of_line());
+}
+
+fn print_preview_line(screen: &mut dyn Screen, line: Option<&String>, width: u16) {
+    if let Some(line) = line {
+        screen.write(&preview::render_line(line, width as usize));
+    }
+}
+
+fn print_matches_row(
+    screen: &mut dyn Screen,
+    search: &Search,
+    theme: &Theme,
+    symbols: &Symbols,
     max_width: u16,
-    visible_choices: u16,
-    selections: &IndexSet<String>,
+    row: usize,
 ) {
-    let visible_choices = visible_choices as usize;
-    for row in 0..visible_choices {
-        if let Some(choice) = matches.get(scroll_offset + row) {
-            let indices = matching::visual_score(choice, query);
-            let mut annotated_choice = (*choice).to_string();
-            if selections.contains(&annotated_choice) {
-                annotated_choice.push_str(" ✓");
+    if let Some(i) = search.matches.get(search.scroll_offset + row) {
+        let choice = &search.choices[i];
+        let indices = matching::visual_score(choice, &search.query, search.case);
+        let highlighted = row == search.cursor_index;
+        let gutter_width = symbols.gutter_width();
+        if gutter_width > 0 {
+            let blank = |s: &str| " ".repeat(UnicodeWidthStr::width(s));
+            if highlighted {
+                screen.write_styled(&theme.pointer, &symbols.pointer);
+            } else {
+                screen.write(&blank(&symbols.pointer));
             }
-            print_match(
-                &annotated_choice,
-                &indices,
-                max_width,
-                &mut |s, highlight| {
-                    if row == cursor_index {
-                        if highlight {
-                            screen.write_red_inverted(s);
-                        } else {
-                            screen.write_inverted(s);
-                        }
-                    } else if highlight {
-                        screen.write_red(s);
-                    } else {
-                        screen.write(s);
-                    }
-                },
-            );
-        }
-        screen.write_bytes(ansi::clear_to_end_of_line());
-        if row + 1 < visible_choices {
-            screen.write(NEWLINE);
+            if search.selections.contains(choice.as_str()) {
+                screen.write_styled(&theme.marker, &symbols.marker);
+            } else {
+                screen.write(&blank(&symbols.marker));
+            }
+            screen.write(" ");
         }
+        let hl_cursor = theme.cursor.overlay(&theme.hl_cursor);
+        let plain = TextStyle::default();
+        print_match(
+            choice.display(),
+            &indices,
+            choice.styles(),
+            max_width.saturating_sub(gutter_width),
+            search.keep_basename,
+            &mut |s, highlight, style| {
+                let theme_style = match (highlighted, highlight) {
+                    (true, true) => &hl_cursor,
+                    (true, false) => &theme.cursor,
+                    (false, true) => &theme.hl,
+                    (false, false) => &plain,
+                };
+                screen.write(style);
+                screen.write_bytes(theme_style.sgr().as_bytes());
+                screen.write(s);
+                if !style.is_empty() || !theme_style.is_plain() {
+                    screen.write_bytes(ansi::reset());
+                }
+            },
+        );
     }
 }
 
+fn print_header_row(
+    screen: &mut dyn Screen,
+    theme: &Theme,
+    symbols: &Symbols,
+    max_width: u16,
+    line: &Choice,
+) {
+    // The header lines up with the matches.
+    let gutter_width = symbols.gutter_width();
+    screen.write(&" ".repeat(gutter_width as usize));
+    print_match(
+        line.display(),
+        &[],
+        line.styles(),
+        max_width.saturating_sub(gutter_width),
+        false,
+        &mut |s, _, style| {
+            screen.write(style);
+            screen.write_bytes(theme.header.sgr().as_bytes());
+            screen.write(s);
+            if !style.is_empty() || !theme.header.is_plain() {
+                screen.write_bytes(ansi::reset());
+            }
+        },
+    );
+}
+
 fn print_match(
     choice: &str,
     indices: &[usize],
+    styles: &[Style],
     max_width: u16,
-    writer: &mut dyn FnMut(&str, bool),
+    keep_basename: bool,
+    writer: &mut dyn FnMut(&str, bool, &str),
 ) {
-    #[cfg(windows)]
-    const MARGIN: u16 = 1;
-    #[cfg(not(windows))]
-    const MARGIN: u16 = 0;
-    let max_width = max_width - MARGIN;
-    let chars_in_choice = choice.chars().count();
-    let mut chars_to_draw = min(chars_in_choice, max_width as usize);
-    while UnicodeWidthStr::width(slice_chars(choice, 0, chars_to_draw)) > max_width as usize {
-        chars_to_draw -= 1;
-    }
-    let mut last_idx = 0;
-    for &idx in indices {
-        let idx = min(idx, chars_to_draw);
-        if last_idx >= chars_to_draw {
-            return;
+    // Choices read with --read0 may contain newlines, which are shown as a symbol instead.
+    let choice = &*if choice.contains('\n') {
+        Cow::Owned(choice.replace('\n', "␤"))
+    } else {
+        Cow::Borrowed(choice)
+    };
+    let max_width = max_width.saturating_sub(MARGIN);
+    let widths: Vec<usize> = choice
+        .chars()
+        .map(|c| UnicodeWidthChar::width(c).unwrap_or(0))
+        .collect();
+    let basename =
+        keep_basename.then(|| matching::basename_start(&choice.chars().collect::<Vec<_>>()));
+    let (head_end, tail) = visible_chars(&widths, indices, basename, max_width as usize);
+    let offsets: Vec<usize> = choice
+        .char_indices()
+        .map(|(offset, _)| offset)
+        .chain([choice.len()])
+        .collect();
+
+    let mut indices = indices.iter().peekable();
+    let mut styles = styles.iter().peekable();
+    let mut style = "";
+    // The byte offset where the current run starts, along with its highlighting and style.
+    let mut run: Option<(usize, bool, &str)> = None;
+    for idx in (0..head_end).chain(tail.clone()) {
+        while indices.next_if(|&&i| i < idx).is_some() {}
+        let highlight = indices.next_if_eq(&&idx).is_some();
+        while let Some((_, next)) = styles.next_if(|(start, _)| *start <= idx) {
+            style = next;
         }
-        writer(slice_chars(choice, last_idx, idx), false);
-        if idx == chars_to_draw {
-            return;
+        if idx == tail.start && head_end < tail.start {
+            if let Some((start, run_highlight, run_style)) = run.take() {
+                writer(&choice[start..offsets[head_end]], run_highlight, run_style);
+            }
+            writer(ELLIPSIS, false, "");
         }
-        writer(slice_chars(choice, idx, idx + 1), true);
-        last_idx = idx + 1;
+        let offset = offsets[idx];
+        match run {
+            Some((_, run_highlight, run_style))
+                if run_highlight == highlight && run_style == style => {}
+            Some((start, run_highlight, run_style)) => {
+                writer(&choice[start..offset], run_highlight, run_style);
+                run = Some((offset, highlight, style));
+            }
+            None => run = Some((offset, highlight, style)),
+        }
+    }
+    if let Some((start, highlight, style)) = run {
+        writer(&choice[start..offsets[tail.end]], highlight, style);
     }
-    writer(slice_chars(choice, last_idx, chars_to_draw), false);
 }
 
-fn read_choices() -> Vec<String> {
-    let stdin = stdin();
-    let mut lines = Vec::new();
-
-    let mut stdin = stdin.lock();
-    let mut first_error = None;
-    let mut suppressed = 0;
-    loop {
-        let mut s = String::new();
-        match stdin.read_line(&mut s) {
-            Ok(_) => {
-                if s.is_empty() {
-                    break;
-                }
-                trim(&mut s);
-                lines.push(s);
-            }
-            Err(e) => {
-                if first_error.is_some() {
-                    suppressed += 1;
-                } else {
-                    first_error = Some(e);
-                }
-            }
+// Chooses which characters of a choice to draw in `max_width` columns, given the width of each of
+// its characters. The characters from the first match to the last one (and from `basename` to the
+// end, if it's given) are kept visible by eliding the characters before them, and as much of the
+// end of that range as fits is kept if it doesn't all fit. Returns the number of characters to
+// draw from the start, followed by the range of characters to draw after an ellipsis, which is
+// only drawn if the range doesn't start right after the first characters.
+fn visible_chars(
+    widths: &[usize],
+    indices: &[usize],
+    basename: Option<usize>,
+    max_width: usize,
+) -> (usize, Range<usize>) {
+    let len = widths.len();
+    // Returns the index after the last character that fits in `width` columns from `start`.
+    let fit = |start: usize, mut width: usize, limit: usize| {
+        let mut end = start;
+        while end < limit && widths[end] <= width {
+            width -= widths[end];
+            end += 1;
         }
+        end
+    };
+    let matched = indices
+        .first()
+        .map(|&first| first..indices[indices.len() - 1] + 1);
+    let kept = match (matched, basename) {
+        (Some(matched), Some(basename)) => min(matched.start, basename)..len,
+        (Some(matched), None) => matched,
+        (None, Some(basename)) => basename..len,
+        (None, None) => 0..0,
+    };
+    if widths[..kept.end].iter().sum::<usize>() <= max_width || max_width == 0 {
+        return (0, 0..fit(0, max_width, len));
     }
-    if let Some(err) = first_error {
-        eprintln!(
-            "Warning: Failed to parse one or more lines (\"{}\"); {} additional error(s) suppressed",
-            err,
-            suppressed
-        );
+
+    // Some of the kept characters have to go, starting with the earliest ones.
+    let room = max_width - ELLIPSIS_WIDTH;
+    let mut start = kept.start;
+    let mut kept_width: usize = widths[kept.clone()].iter().sum();
+    while kept_width > room {
+        kept_width -= widths[start];
+        start += 1;
+    }
+    if start > kept.start {
+        return (0, start..kept.end);
     }
 
-    lines
+    // Any room that's left over shows some of what follows the kept characters, and then the start
+    // of the choice.
+    let spare = room - kept_width;
+    let end = fit(kept.end, spare / 2, len);
+    let spare = spare - widths[kept.end..end].iter().sum::<usize>();
+    let head_end = fit(0, spare, start);
+    let spare = spare - widths[..head_end].iter().sum::<usize>();
+    (head_end, start..fit(end, spare, len))
 }
 
 pub fn trim(s: &mut String) {
@@ -521,41 +1666,58 @@ fn delete_last_word(s: &mut String) {
     }
 }
 
-fn slice_chars(s: &str, begin: usize, end: usize) -> &str {
-    assert!(begin <= end);
-    let mut count = 0;
-    let mut begin_byte = None;
-    let mut end_byte = None;
-
-    // This could be even more efficient by not decoding,
-    // only finding the char boundaries
-    for (idx, _) in s.char_indices() {
-        if count == begin {
-            begin_byte = Some(idx);
-        }
-        if count == end {
-            end_byte = Some(idx);
-            break;
-        }
-        count += 1;
-    }
-    if begin_byte.is_none() && count == begin {
-        begin_byte = Some(s.len());
-    }
-    if end_byte.is_none() && count == end {
-        end_byte = Some(s.len());
-    }
+// Returns the byte offset of the start of the word before `idx`, skipping over any non-alphanumeric
+// characters in between.
+fn previous_word_start(s: &str, idx: usize) -> usize {
+    s[..idx]
+        .char_indices()
+        .rev()
+        .skip_while(|&(_, c)| !c.is_alphanumeric())
+        .take_while(|&(_, c)| c.is_alphanumeric())
+        .last()
+        .map_or(0, |(i, _)| i)
+}
 
-    match (begin_byte, end_byte) {
-        (None, _) => panic!("slice_chars: `begin` is beyond end of string"),
-        (_, None) => panic!("slice_chars: `end` is beyond end of string"),
-        (Some(a), Some(b)) => unsafe { s.get_unchecked(a..b) },
-    }
+// Returns the byte offset of the end of the word after `idx`, skipping over any non-alphanumeric
+// characters in between.
+fn next_word_end(s: &str, idx: usize) -> usize {
+    s[idx..]
+        .char_indices()
+        .skip_while(|&(_, c)| !c.is_alphanumeric())
+        .find(|&(_, c)| !c.is_alphanumeric())
+        .map_or(s.len(), |(i, _)| idx + i)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{delete_last_word, trim};
+    use super::{
+        DOUBLE_CLICK_INTERVAL, EXIT_CANCELED, EXIT_NO_MATCH, EXIT_SELECTED, Layout, Region, Row,
+        Search, SearchState, Symbols, delete_last_word, trim, visible_chars,
+    };
+    use crate::matching::{self, CaseMode, Choice};
+    use crate::preview::PreviewPosition;
+    use std::time::{Duration, Instant};
+
+    fn search_with(choices: &[&str]) -> Search {
+        let mut search = Search::new(String::new(), false, CaseMode::Smart);
+        search.add_choices(choices.iter().map(|s| Choice::new(s.to_string())).collect());
+        search.recompute_matches();
+        search
+    }
+
+    fn type_query(search: &mut Search, query: &str) {
+        for c in query.chars() {
+            search.insert(c);
+            search.recompute_matches();
+        }
+    }
+
+    fn matches(search: &mut Search) -> Vec<&str> {
+        search.matches.sort_to(search.matches.len());
+        (0..search.matches.len())
+            .map(|rank| search.choices[search.matches.get(rank).unwrap()].as_str())
+            .collect()
+    }
 
     #[test]
     fn trim_test() {
@@ -587,4 +1749,359 @@ mod tests {
         should_become("asdf asdf asdf ", "asdf asdf ");
         should_become("asdf asdf asdf  ", "asdf asdf ");
     }
+
+    #[test]
+    fn query_editing() {
+        let mut search = Search::new("foo bar".to_string(), false, CaseMode::Smart);
+        assert_eq!(search.query_cursor, 7);
+        search.backward_word();
+        search.insert('x');
+        assert_eq!(search.query, "foo xbar");
+        search.backward_char();
+        search.backward_char();
+        search.delete_char();
+        search.backspace();
+        assert_eq!(search.query, "foxbar");
+        assert_eq!(search.query_cursor, 2);
+
+        search.kill_line();
+        assert_eq!(search.query, "fo");
+        search.beginning_of_line();
+        search.yank();
+        assert_eq!(search.query, "xbarfo");
+        assert_eq!(search.query_cursor, 4);
+
+        search.end_of_line();
+        search.forward_char();
+        assert_eq!(search.query_cursor, 6);
+        search.clear_query();
+        search.yank();
+        search.yank();
+        assert_eq!(search.query, "xbarfoxbarfo");
+    }
+
+    #[test]
+    fn word_movement() {
+        let mut search = Search::new("é-foo  bar.baz".to_string(), false, CaseMode::Smart);
+        search.backward_word();
+        assert_eq!(&search.query[search.query_cursor..], "baz");
+        search.backward_word();
+        search.backward_word();
+        assert_eq!(&search.query[search.query_cursor..], "foo  bar.baz");
+        search.forward_word();
+        assert_eq!(&search.query[search.query_cursor..], "  bar.baz");
+        search.kill_word();
+        assert_eq!(search.query, "é-foo.baz");
+        search.delete_word();
+        assert_eq!(search.query, ".baz");
+        search.backward_word();
+        assert_eq!(search.query_cursor, 0);
+        search.forward_word();
+        search.forward_word();
+        assert_eq!(search.query_cursor, search.query.len());
+    }
+
+    #[test]
+    fn backspace_restores_cached_matches() {
+        let mut search = search_with(&["foo", "bar", "baz"]);
+        type_query(&mut search, "baz");
+        assert_eq!(matches(&mut search), vec!["baz"]);
+        assert_eq!(cached_queries(&search), ["", "b", "ba"]);
+
+        search.backspace();
+        search.recompute_matches();
+        assert!(!search.is_matching());
+        assert_eq!(matches(&mut search), vec!["bar", "baz"]);
+        assert_eq!(cached_queries(&search), ["", "b"]);
+
+        search.clear_query();
+        search.recompute_matches();
+        assert!(!search.is_matching());
+        assert_eq!(matches(&mut search), vec!["foo", "bar", "baz"]);
+        assert_eq!(cached_queries(&search), [""; 0]);
+    }
+
+    fn cached_queries(search: &Search) -> Vec<&str> {
+        search
+            .match_cache
+            .iter()
+            .map(|cached| cached.query.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn highlight_stays_on_a_match_when_results_shrink() {
+        let mut search = search_with(&["bar", "baz", "bat", "bag"]);
+        search.insert('b');
+        search.recompute_matches();
+        // The user moves down while the matches for the longer query are still being computed.
+        search.insert('a');
+        search.insert('t');
+        search.down(10);
+        search.down(10);
+        search.down(10);
+        search.recompute_matches();
+        assert_eq!(search.matches.len(), 1);
+        assert_eq!(search.scroll_offset + search.cursor_index, 0);
+        search.done();
+        assert_eq!(search.get_selections(false), "bat");
+    }
+
+    #[test]
+    fn highlight_stays_put_when_no_matches_fit() {
+        let mut search = search_with(&["a", "b"]);
+        search.recompute_matches();
+        search.down(0);
+        search.up(0);
+        search.end(0);
+        search.pgdown(0);
+        assert_eq!(search.scroll_offset + search.cursor_index, 0);
+        search.done();
+        assert_eq!(search.get_selections(false), "a");
+    }
+
+    #[test]
+    fn cached_matches_pick_up_new_choices() {
+        let mut search = search_with(&["foo", "bar"]);
+        type_query(&mut search, "ba");
+        search.add_choices(vec![
+            Choice::new("bat".to_string()),
+            Choice::new("cat".to_string()),
+        ]);
+        search.recompute_matches();
+        assert_eq!(matches(&mut search), vec!["bar", "bat"]);
+
+        search.backspace();
+        search.recompute_matches();
+        assert_eq!(matches(&mut search), vec!["bar", "bat"]);
+
+        search.backspace();
+        search.recompute_matches();
+        assert_eq!(matches(&mut search), vec!["foo", "bar", "bat", "cat"]);
+    }
+
+    #[test]
+    fn widening_queries_are_not_narrowed() {
+        let mut search = search_with(&["ab", "ac", "b"]);
+        type_query(&mut search, "!a");
+        assert_eq!(matches(&mut search), vec!["b"]);
+        type_query(&mut search, "b");
+        assert_eq!(matches(&mut search), vec!["ac", "b"]);
+
+        search.delete_word();
+        type_query(&mut search, "a !c");
+        assert_eq!(matches(&mut search), vec!["ab"]);
+    }
+
+    #[test]
+    fn selections_can_be_nul_terminated() {
+        let mut search = search_with(&["foo", "bar\nbaz"]);
+        assert_eq!(search.get_selections(false), "foo");
+        assert_eq!(search.get_selections(true), "foo\0");
+
+        search.toggle_selection();
+        search.down(10);
+        search.toggle_selection();
+        assert_eq!(search.get_selections(true), "foo\0bar\nbaz\0");
+    }
+
+    #[test]
+    fn clicking_matches() {
+        let mut search = search_with(&["foo", "bar", "baz"]);
+        let start = Instant::now();
+        search.click(1, start);
+        assert_eq!(search.cursor_index, 1);
+        search.click(2, start);
+        search.click(5, start);
+        assert_eq!(search.cursor_index, 2);
+        search.click(1, start);
+        search.click(1, start + DOUBLE_CLICK_INTERVAL);
+        assert!(search.state == SearchState::InProgress);
+        search.click(
+            1,
+            start + DOUBLE_CLICK_INTERVAL + Duration::from_millis(100),
+        );
+        assert!(search.state == SearchState::Done);
+        assert_eq!(search.get_selections(false), "bar");
+    }
+
+    #[test]
+    fn layout_regions() {
+        let layout = |preview, reverse| Layout {
+            width: 80,
+            rows: 10,
+            header_rows: 0,
+            choice_rows: if preview == Some(PreviewPosition::Bottom) {
+                5
+            } else {
+                10
+            },
+            choice_width: if preview == Some(PreviewPosition::Right) {
+                40
+            } else {
+                80
+            },
+            preview,
+            reverse,
+        };
+        // The prompt is on row 20, and the last row of matches is on row 30.
+        let plain = layout(None, false);
+        assert_eq!(plain.region(30, 20, 0), Region::Other);
+        assert_eq!(plain.region(30, 21, 0), Region::Choice(0));
+        assert_eq!(plain.region(30, 30, 79), Region::Choice(9));
+        assert_eq!(plain.region(30, 31, 0), Region::Other);
+
+        let right = layout(Some(PreviewPosition::Right), false);
+        assert_eq!(right.region(30, 22, 39), Region::Choice(1));
+        assert_eq!(right.region(30, 22, 40), Region::Preview);
+
+        let bottom = layout(Some(PreviewPosition::Bottom), false);
+        assert_eq!(bottom.region(30, 25, 0), Region::Choice(4));
+        assert_eq!(bottom.region(30, 26, 0), Region::Other);
+        assert_eq!(bottom.region(30, 27, 0), Region::Preview);
+        assert_eq!(bottom.row(6), Row::Preview(0));
+
+        // In the reverse layout, the prompt is on row 30, and the best match is right above it.
+        let reverse = layout(None, true);
+        assert_eq!(reverse.region(30, 30, 0), Region::Other);
+        assert_eq!(reverse.region(30, 29, 0), Region::Choice(0));
+        assert_eq!(reverse.region(30, 20, 0), Region::Choice(9));
+        assert_eq!(reverse.region(30, 19, 0), Region::Other);
+
+        // The preview is above the matches, and still reads from top to bottom.
+        let reverse_bottom = layout(Some(PreviewPosition::Bottom), true);
+        assert_eq!(reverse_bottom.row(0), Row::Preview(0));
+        assert_eq!(reverse_bottom.row(3), Row::Preview(3));
+        assert_eq!(reverse_bottom.row(4), Row::Border);
+        assert_eq!(reverse_bottom.row(5), Row::Choice(4));
+        assert_eq!(reverse_bottom.row(9), Row::Choice(0));
+        assert_eq!(reverse_bottom.region(30, 23, 0), Region::Preview);
+        assert_eq!(reverse_bottom.region(30, 29, 0), Region::Choice(0));
+    }
+
+    #[test]
+    fn header_lines() {
+        let mut search = Search::new(String::new(), false, CaseMode::Smart);
+        search.header_lines = 2;
+        search.add_choices(vec![Choice::new("PID CMD".to_string())]);
+        search.add_choices(
+            ["1 init", "2 sh", "3 hs"]
+                .map(|s| Choice::new(s.to_string()))
+                .into(),
+        );
+        search.recompute_matches();
+        let header: Vec<&str> = search.header.iter().map(Choice::as_str).collect();
+        assert_eq!(header, ["PID CMD", "1 init"]);
+        assert_eq!(matches(&mut search), ["2 sh", "3 hs"]);
+
+        // The header is between the prompt and the matches, but reads from top to bottom either
+        // way.
+        let layout = |reverse| Layout {
+            width: 80,
+            rows: 10,
+            header_rows: 2,
+            choice_rows: 8,
+            choice_width: 80,
+            preview: None,
+            reverse,
+        };
+        assert_eq!(layout(false).row(0), Row::Header(0));
+        assert_eq!(layout(false).row(1), Row::Header(1));
+        assert_eq!(layout(false).row(2), Row::Choice(0));
+        assert_eq!(layout(false).region(30, 22, 0), Region::Other);
+        assert_eq!(layout(false).region(30, 23, 0), Region::Choice(0));
+        assert_eq!(layout(true).row(7), Row::Choice(0));
+        assert_eq!(layout(true).row(8), Row::Header(0));
+        assert_eq!(layout(true).row(9), Row::Header(1));
+    }
+
+    // Returns what `visible_chars` draws of `s`, where every character is one column wide.
+    fn elide(s: &str, indices: &[usize], keep_basename: bool, max_width: usize) -> String {
+        let chars: Vec<char> = s.chars().collect();
+        let basename = keep_basename.then(|| matching::basename_start(&chars));
+        let (head_end, tail) = visible_chars(&vec![1; chars.len()], indices, basename, max_width);
+        let mut visible: String = chars[..head_end].iter().collect();
+        if head_end < tail.start {
+            visible.push('…');
+        }
+        visible.extend(&chars[tail]);
+        visible
+    }
+
+    #[test]
+    fn long_choices_keep_matches_visible() {
+        let path = "src/components/widgets/dialog.rs";
+        // Choices are only cut off at the end when the matches are near the start.
+        assert_eq!(elide(path, &[], false, 10), "src/compon");
+        assert_eq!(elide(path, &[0, 1, 2], false, 10), "src/compon");
+        assert_eq!(elide(path, &[0, 1, 2], false, 40), path);
+
+        // Otherwise, the start of the choice is elided.
+        assert_eq!(elide(path, &[23, 24, 25], false, 10), "src…dialog");
+        assert_eq!(
+            elide(path, &[23, 24, 25], false, 20),
+            "src/compon…dialog.rs"
+        );
+        // When the matches don't all fit, the last ones are kept.
+        assert_eq!(elide(path, &[4, 30], false, 10), "…/dialog.r");
+
+        // The basename is kept even if it isn't matched.
+        assert_eq!(elide(path, &[], true, 12), "sr…dialog.rs");
+        assert_eq!(elide(path, &[4], true, 12), "…s/dialog.rs");
+        assert_eq!(elide(path, &[4], true, 40), path);
+    }
+
+    #[test]
+    fn gutter_width() {
+        let symbols = |pointer: &str, marker: &str| Symbols {
+            prompt: "> ".to_string(),
+            pointer: pointer.to_string(),
+            marker: marker.to_string(),
+        };
+        assert_eq!(symbols("", "✓").gutter_width(), 2);
+        assert_eq!(symbols("▶", "✓").gutter_width(), 3);
+        assert_eq!(symbols("", "選").gutter_width(), 3);
+        assert_eq!(symbols("", "").gutter_width(), 0);
+    }
+
+    #[test]
+    fn exit_status_reflects_the_outcome() {
+        let mut search = search_with(&["foo", "bar"]);
+        search.done();
+        search.get_selections(false);
+        assert_eq!(search.exit_status(), EXIT_SELECTED);
+
+        type_query(&mut search, "zzz");
+        search.get_selections(false);
+        assert_eq!(search.exit_status(), EXIT_NO_MATCH);
+
+        // Choices selected before the query stopped matching them are still printed.
+        search.clear_query();
+        search.recompute_matches();
+        search.toggle_selection();
+        type_query(&mut search, "zzz");
+        search.get_selections(false);
+        assert_eq!(search.exit_status(), EXIT_SELECTED);
+
+        search.cancel();
+        assert_eq!(search.exit_status(), EXIT_CANCELED);
+    }
+
+    #[test]
+    fn newer_queries_replace_running_searches() {
+        let mut search = search_with(&["foo", "bar", "baz"]);
+        search.insert('b');
+        search.update_matches(Some(Duration::ZERO));
+        search.add_choices(vec![Choice::new("bat".to_string())]);
+        search.insert('a');
+        search.update_matches(Some(Duration::ZERO));
+        search.insert('t');
+        search.recompute_matches();
+        assert!(!search.is_matching());
+        assert_eq!(matches(&mut search), vec!["bat"]);
+
+        search.backspace();
+        search.recompute_matches();
+        assert_eq!(matches(&mut search), vec!["bar", "baz", "bat"]);
+    }
 }
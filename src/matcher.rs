@@ -0,0 +1,122 @@
+use crate::matching::{self, CaseMode, Choice, Matches};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Matching is done on a background thread so that the UI stays responsive while a large input is
+// being searched. Starting a new search cancels the previous one, and only the results of the
+// most recently started search are ever returned.
+pub struct Matcher {
+    jobs: Sender<Job>,
+    results: Receiver<Option<Matches>>,
+    // The number of searches whose results haven't been received yet.
+    outstanding: usize,
+    // Set when the most recently started search is no longer wanted.
+    canceled: Arc<AtomicBool>,
+}
+
+struct Job {
+    choices: Arc<Vec<Choice>>,
+    candidates: Vec<usize>,
+    query: String,
+    case: CaseMode,
+    filter_only: bool,
+    canceled: Arc<AtomicBool>,
+}
+
+impl Matcher {
+    pub fn spawn() -> Matcher {
+        let (jobs_tx, jobs_rx) = mpsc::channel();
+        let (results_tx, results_rx) = mpsc::channel();
+        thread::spawn(move || run_jobs(&jobs_rx, &results_tx));
+        Matcher {
+            jobs: jobs_tx,
+            results: results_rx,
+            outstanding: 0,
+            canceled: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    // Starts matching the given candidates (indices into `choices`) against the query, canceling
+    // the previous search if it's still running.
+    pub fn start(
+        &mut self,
+        choices: Arc<Vec<Choice>>,
+        candidates: Vec<usize>,
+        query: &str,
+        case: CaseMode,
+        filter_only: bool,
+    ) {
+        self.cancel();
+        self.canceled = Arc::new(AtomicBool::new(false));
+        let job = Job {
+            choices,
+            candidates,
+            query: query.to_string(),
+            case,
+            filter_only,
+            canceled: self.canceled.clone(),
+        };
+        self.jobs.send(job).unwrap();
+        self.outstanding += 1;
+    }
+
+    pub fn cancel(&self) {
+        self.canceled.store(true, Relaxed);
+    }
+
+    // Returns true until the results of every search (including canceled ones) have been
+    // received. The choices passed to `start` aren't released until then.
+    pub fn is_busy(&self) -> bool {
+        self.outstanding > 0
+    }
+
+    // Waits up to `timeout` (or indefinitely, if it's None) for every search to finish, then
+    // returns the matches for the most recent one, unless it was canceled.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> Option<Matches> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut latest = None;
+        while self.outstanding > 0 {
+            latest = match deadline {
+                None => self.results.recv().unwrap(),
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    match self.results.recv_timeout(remaining) {
+                        Ok(result) => result,
+                        Err(RecvTimeoutError::Timeout) => return None,
+                        Err(RecvTimeoutError::Disconnected) => panic!("matcher thread exited"),
+                    }
+                }
+            };
+            self.outstanding -= 1;
+        }
+        latest.filter(|_| !self.canceled.load(Relaxed))
+    }
+}
+
+fn run_jobs(jobs: &Receiver<Job>, results: &Sender<Option<Matches>>) {
+    for job in jobs {
+        // Skip straight past searches that were canceled while they were queued.
+        let matches = if job.canceled.load(Relaxed) {
+            None
+        } else {
+            matching::compute_matches_until(
+                &job.choices,
+                &job.candidates,
+                &job.query,
+                job.case,
+                job.filter_only,
+                &job.canceled,
+            )
+        };
+        // The choices have to be released before the results are sent, so that the UI can append
+        // to them as soon as it sees that the matcher is idle.
+        drop(job);
+        if results.send(matches).is_err() {
+            return;
+        }
+    }
+}
@@ -1,13 +1,137 @@
 use rayon::prelude::*;
 use std::cmp::*;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
 
+// Bonuses awarded to matched characters, as a fraction of the character's share of the score. A
+// run of consecutive matches inherits the bonus of the character that begins it, so that `bar`
+// matching the word "bar" in "foo_bar" is rewarded as a whole.
+const BOUNDARY_BONUS: f64 = 0.5;
+const CAMEL_CASE_BONUS: f64 = 0.4;
+const BASENAME_BONUS: f64 = 0.3;
+
+#[cfg(test)]
 macro_rules! chars {
     ($str:expr) => {
         &$str.chars().collect::<Vec<char>>()
     };
 }
 
-#[derive(PartialEq)]
+// Evaluates `$body` with `$chars` bound to the characters of the choice's key, as either a `&[u8]`
+// (for ASCII keys) or a `&[char]`.
+macro_rules! with_chars {
+    ($choice:expr, |$chars:ident| $body:expr) => {
+        match &$choice.chars {
+            None => {
+                let $chars = $choice.key().as_bytes();
+                $body
+            }
+            Some(chars) => {
+                let $chars = &chars[..];
+                $body
+            }
+        }
+    };
+}
+
+// A choice, preprocessed when it is read so that matching it doesn't require any allocation. Pure
+// ASCII choices (the overwhelmingly common case) are matched directly against their bytes, while
+// the characters of any other choice are decoded once, up front.
+pub struct Choice {
+    text: String,
+    // Boxed, since most choices don't use fields or colors.
+    details: Option<Box<ChoiceDetails>>,
+    // The characters of the key, if it isn't ASCII.
+    chars: Option<Box<[char]>>,
+}
+
+// The parts of a choice that are displayed and matched against, when they aren't the whole thing,
+// and the colors it's displayed in.
+#[derive(Default)]
+struct ChoiceDetails {
+    display: Option<String>,
+    // The text that is matched against, along with the index within the displayed text of each of
+    // its characters.
+    key: Option<(String, Box<[usize]>)>,
+    styles: Box<[Style]>,
+}
+
+// The index of a character in the displayed text of a choice, along with the SGR escape sequences
+// that style it and every following character, up until the next style.
+pub type Style = (usize, String);
+
+impl Choice {
+    pub fn new(text: String) -> Choice {
+        Choice::with_fields(text, None, None)
+    }
+
+    // Creates a choice that displays `display` instead of its text, and that is matched against
+    // `key` (a subset of the displayed text) instead of all of it.
+    pub fn with_fields(
+        text: String,
+        display: Option<String>,
+        key: Option<(String, Vec<usize>)>,
+    ) -> Choice {
+        let details = if display.is_none() && key.is_none() {
+            None
+        } else {
+            Some(Box::new(ChoiceDetails {
+                display,
+                key: key.map(|(key, offsets)| (key, offsets.into_boxed_slice())),
+                styles: Box::default(),
+            }))
+        };
+        let mut choice = Choice {
+            text,
+            details,
+            chars: None,
+        };
+        if !choice.key().is_ascii() {
+            choice.chars = Some(choice.key().chars().collect());
+        }
+        choice
+    }
+
+    pub fn with_styles(mut self, styles: Vec<Style>) -> Choice {
+        if !styles.is_empty() {
+            self.details.get_or_insert_default().styles = styles.into_boxed_slice();
+        }
+        self
+    }
+
+    // Returns the original text of the choice, which is what gets printed when it's selected.
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    pub fn display(&self) -> &str {
+        self.details
+            .as_ref()
+            .and_then(|details| details.display.as_deref())
+            .unwrap_or(&self.text)
+    }
+
+    pub fn styles(&self) -> &[Style] {
+        self.details.as_ref().map_or(&[], |details| &details.styles)
+    }
+
+    fn key(&self) -> &str {
+        self.details
+            .as_ref()
+            .and_then(|details| details.key.as_ref())
+            .map_or_else(|| self.display(), |(key, _)| key)
+    }
+
+    // Converts an index into the key into an index into the displayed text.
+    fn display_index(&self, idx: usize) -> usize {
+        self.details
+            .as_ref()
+            .and_then(|details| details.key.as_ref())
+            .map_or(idx, |(_, offsets)| offsets[idx])
+    }
+}
+
+#[derive(PartialEq, Clone, Copy)]
 struct ScoredChoice {
     idx: usize,
     score: f64,
@@ -25,171 +149,663 @@ impl PartialOrd for ScoredChoice {
     }
 }
 
-pub fn compute_matches<'a>(choices: &[&'a str], query: &str, filter_only: bool) -> Vec<&'a str> {
-    let par_choices = choices.par_iter().with_min_len(100);
+fn compare(x: &ScoredChoice, y: &ScoredChoice) -> Ordering {
+    x.partial_cmp(y).unwrap()
+}
 
-    if filter_only {
-        return par_choices
-            .filter_map(|choice| {
-                let choice = *choice;
-                if filter(choice, query) > 0.0 {
-                    Some(choice)
-                } else {
-                    None
-                }
-            })
-            .collect();
+// The choices that matched a query, best match first. Ranking is done lazily: since only a
+// screenful of matches is ever displayed at a time, sorting every match up front would mostly be
+// wasted work. Instead, callers ask for the top `n` matches to be ranked with `sort_to` before
+// looking them up with `get`.
+#[derive(Clone, Default)]
+pub struct Matches {
+    scored: Vec<ScoredChoice>,
+    // The number of matches at the front of `scored` that are in their final order.
+    sorted: usize,
+}
+
+impl Matches {
+    pub fn len(&self) -> usize {
+        self.scored.len()
     }
 
-    let mut scored: Vec<_> = par_choices
-        .enumerate()
-        .map(|(i, choice)| {
-            let choice = *choice;
-            let score = score(choice, query);
-            ScoredChoice { idx: i, score }
-        })
-        .filter(|scored_choice| scored_choice.score > 0.0)
-        .collect();
+    pub fn is_empty(&self) -> bool {
+        self.scored.is_empty()
+    }
 
-    scored.sort_by(|x, y| x.partial_cmp(y).unwrap());
-    scored.into_iter().map(|x| choices[x.idx]).collect()
-}
+    // Returns the index of the choice with the given rank, which must already have been sorted.
+    pub fn get(&self, rank: usize) -> Option<usize> {
+        debug_assert!(rank < self.sorted || rank >= self.len());
+        self.scored.get(rank).map(|scored| scored.idx)
+    }
 
-fn score(choice: &str, query: &str) -> f64 {
-    if query.is_empty() {
-        return 1.0;
+    // Ranks the top `n` matches, leaving the rest in no particular order.
+    pub fn sort_to(&mut self, n: usize) {
+        let n = min(n, self.len());
+        if n <= self.sorted {
+            return;
+        }
+        let unsorted = &mut self.scored[self.sorted..];
+        let count = n - self.sorted;
+        if count < unsorted.len() {
+            unsorted.select_nth_unstable_by(count - 1, compare);
+        }
+        unsorted[..count].sort_unstable_by(compare);
+        self.sorted = n;
     }
-    if choice.is_empty() {
-        return 0.0;
+
+    // Returns the indices of every matching choice, in no particular order.
+    pub fn choices(&self) -> impl Iterator<Item = usize> + '_ {
+        self.scored.iter().map(|scored| scored.idx)
     }
+}
+
+// Controls how letter case is taken into account when matching.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum CaseMode {
+    // Lowercase query characters match either case, uppercase ones only match uppercase.
+    #[default]
+    Smart,
+    Ignore,
+    Respect,
+}
+
+// A parsed search query. Whitespace separates the query into terms, all of which must match. A
+// term is matched fuzzily unless it uses one of the following operators:
+//
+//   'term   exact substring match
+//   ^term   prefix match
+//   term$   suffix match
+//   ^term$  exact match of the entire choice
+//   !term   excludes choices containing `term` (may be combined with `^` and `$`)
+//
+// A backslash escapes a space, allowing it to be included in a term. Terms that are empty once
+// their operators are stripped (e.g. a lone `!` while the user is still typing) are ignored.
+pub struct Query {
+    terms: Vec<Term>,
+    case: CaseMode,
+}
+
+#[derive(Debug, PartialEq)]
+struct Term {
+    text: Vec<char>,
+    kind: TermKind,
+    negated: bool,
+}
 
-    let query = chars!(query);
-    let choice = chars!(choice);
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum TermKind {
+    Fuzzy,
+    Exact,
+    Prefix,
+    Suffix,
+    Equal,
+}
 
-    match compute_match_length(choice, query) {
-        None => 0.0,
-        Some(match_length) => {
-            let score = query.len() as f64 / match_length as f64;
-            score / choice.len() as f64
+impl Query {
+    pub fn parse(query: &str, case: CaseMode) -> Query {
+        let mut terms = Vec::new();
+        let mut token = String::new();
+        let mut chars = query.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c == '\\' && chars.peek().is_some_and(|c| c.is_whitespace()) {
+                token.push(chars.next().unwrap());
+            } else if c.is_whitespace() {
+                terms.extend(Term::parse(&token));
+                token.clear();
+            } else {
+                token.push(c);
+            }
         }
+        terms.extend(Term::parse(&token));
+        Query { terms, case }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    // Returns true if every choice matching `self` is guaranteed to also match `previous`, which
+    // allows the matches for `previous` to be narrowed down instead of starting from scratch.
+    fn narrows(&self, previous: &Query) -> bool {
+        previous
+            .terms
+            .iter()
+            .all(|p| self.terms.iter().any(|term| term.implies(p)))
     }
 }
 
-fn filter(choice: &str, query: &str) -> f64 {
-    if query.is_empty() {
-        return 1.0;
+impl Term {
+    fn parse(token: &str) -> Option<Term> {
+        let mut text = token;
+        let negated = text.starts_with('!');
+        if negated {
+            text = &text[1..];
+        }
+        let kind = if let Some(rest) = text.strip_prefix('\'') {
+            text = rest;
+            TermKind::Exact
+        } else {
+            let prefix = text.starts_with('^');
+            if prefix {
+                text = &text[1..];
+            }
+            let suffix = text.ends_with('$');
+            if suffix {
+                text = &text[..text.len() - 1];
+            }
+            match (prefix, suffix) {
+                (true, true) => TermKind::Equal,
+                (true, false) => TermKind::Prefix,
+                (false, true) => TermKind::Suffix,
+                // Fuzzy exclusion is almost never what anyone wants, so negated terms are exact.
+                (false, false) if negated => TermKind::Exact,
+                (false, false) => TermKind::Fuzzy,
+            }
+        };
+        if text.is_empty() {
+            return None;
+        }
+        Some(Term {
+            text: text.chars().collect(),
+            kind,
+            negated,
+        })
+    }
+
+    // Returns true if this term occurs anywhere in the choice, ignoring negation.
+    fn occurs<C: Copy + Into<char>>(&self, choice: &[C], case: CaseMode) -> bool {
+        let len = self.text.len();
+        if len > choice.len() {
+            return false;
+        }
+        match self.kind {
+            TermKind::Fuzzy => choice
+                .iter()
+                .position(|&c| chars_equal(self.text[0], c.into(), case))
+                .is_some_and(|first| {
+                    find_end_of_match(choice, &self.text[1..], first, case).is_some()
+                }),
+            TermKind::Exact => find_substring(choice, &self.text, case).is_some(),
+            TermKind::Prefix => substring_at(choice, &self.text, 0, case),
+            TermKind::Suffix => substring_at(choice, &self.text, choice.len() - len, case),
+            TermKind::Equal => len == choice.len() && substring_at(choice, &self.text, 0, case),
+        }
     }
-    if choice.is_empty() {
-        return 0.0;
+
+    fn is_match<C: Copy + Into<char>>(&self, choice: &[C], case: CaseMode) -> bool {
+        self.occurs(choice, case) != self.negated
     }
 
-    let query = chars!(query);
-    let choice = chars!(choice);
+    // Returns the score of the highest-scoring match for this term within the choice, along with
+    // the index where that match starts, ignoring negation.
+    fn best_match<C: Copy + Into<char>>(
+        &self,
+        choice: &[C],
+        case: CaseMode,
+    ) -> Option<(f64, usize)> {
+        let len = self.text.len();
+        if len > choice.len() {
+            return None;
+        }
+        let starts = match self.kind {
+            TermKind::Fuzzy | TermKind::Exact => 0..choice.len() - len + 1,
+            TermKind::Prefix => 0..1,
+            TermKind::Suffix => choice.len() - len..choice.len() - len + 1,
+            TermKind::Equal if len == choice.len() => 0..1,
+            TermKind::Equal => return None,
+        };
+        let basename_start = basename_start(choice);
+
+        let mut best: Option<(f64, usize)> = None;
+        for start in starts {
+            let mut scorer = MatchScorer::new(choice, basename_start);
+            if self.kind == TermKind::Fuzzy {
+                if !chars_equal(self.text[0], choice[start].into(), case) {
+                    continue;
+                }
+                scorer.push(start);
+                if !walk_match(choice, &self.text[1..], start, case, |idx| scorer.push(idx)) {
+                    // If the rest of the term can't be found after this point, it can't be found
+                    // after any later one either.
+                    break;
+                }
+            } else if substring_at(choice, &self.text, start, case) {
+                (start..start + len).for_each(|idx| scorer.push(idx));
+            } else {
+                continue;
+            }
+            let score = scorer.score();
+            if best.is_none_or(|(best_score, _)| score > best_score) {
+                best = Some((score, start));
+            }
+        }
+        best
+    }
 
-    match compute_match_length(choice, query) {
-        None => 0.0,
-        Some(_) => 1.0,
+    // Returns the indices of the characters matched by the highest-scoring match for this term.
+    fn indices<C: Copy + Into<char>>(&self, choice: &[C], case: CaseMode) -> Vec<usize> {
+        match self.best_match(choice, case) {
+            Some((_, start)) if self.kind == TermKind::Fuzzy => {
+                get_match_indices(choice, &self.text[1..], start, case).unwrap()
+            }
+            Some((_, start)) => (start..start + self.text.len()).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // Returns true if every choice matching `self` also matches `other`.
+    fn implies(&self, other: &Term) -> bool {
+        use TermKind::*;
+
+        if self == other {
+            return true;
+        }
+        if self.negated != other.negated {
+            return false;
+        }
+        let (narrow, wide) = if self.negated {
+            (other, self)
+        } else {
+            (self, other)
+        };
+        let (text, pattern) = (&narrow.text, &wide.text);
+        match (narrow.kind, wide.kind) {
+            (_, Fuzzy) => text.starts_with(pattern),
+            (Exact | Prefix | Suffix | Equal, Exact) => {
+                text.windows(pattern.len()).any(|w| w == &pattern[..])
+            }
+            (Prefix | Equal, Prefix) => text.starts_with(pattern),
+            (Suffix | Equal, Suffix) => text.ends_with(pattern),
+            _ => false,
+        }
     }
 }
 
-// This function is for picking out the matching characters for a given (choice, query) pair for
-// rendering purposes. It assumes that the given choice is in fact a match for the given query, and
-// will panic if this is not the case.
-pub fn visual_score(choice: &str, query: &str) -> Vec<usize> {
-    if query.is_empty() || choice.is_empty() {
-        return Vec::new();
+// Returns true if narrowing the matches for `previous` is guaranteed to produce the same results
+// as matching `query` against every choice.
+pub fn narrows(previous: &str, query: &str, case: CaseMode) -> bool {
+    Query::parse(query, case).narrows(&Query::parse(previous, case))
+}
+
+// Matches the given candidates (indices into `choices`) against the query. Matches are ranked by
+// score, with ties broken by input order; when `filter_only` is set, every match scores the same,
+// so they simply keep their input order.
+pub fn compute_matches(
+    choices: &[Choice],
+    candidates: &[usize],
+    query: &str,
+    case: CaseMode,
+    filter_only: bool,
+) -> Matches {
+    let never = AtomicBool::new(false);
+    compute_matches_until(choices, candidates, query, case, filter_only, &never).unwrap()
+}
+
+// Like `compute_matches`, but gives up as soon as `canceled` is set, returning None.
+pub fn compute_matches_until(
+    choices: &[Choice],
+    candidates: &[usize],
+    query: &str,
+    case: CaseMode,
+    filter_only: bool,
+    canceled: &AtomicBool,
+) -> Option<Matches> {
+    let query = Query::parse(query, case);
+    let query = &query;
+    let scored = candidates
+        .par_iter()
+        .with_min_len(100)
+        .map(|&i| {
+            if canceled.load(Relaxed) {
+                return None;
+            }
+            let choice = &choices[i];
+            let score = if filter_only {
+                filter(choice, query)
+            } else {
+                score(choice, query)
+            };
+            Some(ScoredChoice { idx: i, score })
+        })
+        .while_some()
+        .filter(|scored_choice| scored_choice.score > 0.0)
+        .collect();
+
+    if canceled.load(Relaxed) {
+        return None;
     }
-    let query = chars!(query);
-    let choice = chars!(choice);
+    Some(Matches { scored, sorted: 0 })
+}
 
-    let (first_idx, _) = get_longest_match(choice, query).unwrap();
-    get_match_indices(choice, &query[1..], first_idx).unwrap()
+fn score(choice: &Choice, query: &Query) -> f64 {
+    if query.is_empty() {
+        return 1.0;
+    }
+    with_chars!(choice, |chars| score_chars(chars, query))
 }
 
-fn compute_match_length(string: &[char], query: &[char]) -> Option<usize> {
-    get_match_length(get_longest_match(string, query))
+fn score_chars<C: Copy + Into<char>>(choice: &[C], query: &Query) -> f64 {
+    let mut score = 1.0;
+    let mut positive_terms = 0;
+    for term in &query.terms {
+        if term.negated {
+            if term.occurs(choice, query.case) {
+                return 0.0;
+            }
+        } else if let Some((term_score, _)) = term.best_match(choice, query.case) {
+            score *= term_score;
+            positive_terms += 1;
+        } else {
+            return 0.0;
+        }
+    }
+
+    // Taking the geometric mean keeps scores comparable regardless of how many terms there are.
+    if positive_terms == 0 {
+        1.0
+    } else {
+        score.powf(1.0 / positive_terms as f64) / choice.len() as f64
+    }
 }
 
-fn get_longest_match(string: &[char], query: &[char]) -> Option<(usize, usize)> {
-    let first_char = query[0];
-    let rest = &query[1..];
-    let indices = find_char_in_string(string, first_char);
+// Scores a single term's match, one matched character at a time. The score starts out as the
+// ratio of the term's length to the length of the span it matched, which penalizes gaps between
+// the matched characters. That ratio is then scaled up by the bonuses earned by the matched
+// characters, favoring matches that start at word boundaries (after `/`, `_`, `-`, `.`, or a
+// space, or at a camelCase hump) and matches in the basename of a path.
+struct MatchScorer<'a, C> {
+    choice: &'a [C],
+    basename_start: usize,
+    first: usize,
+    last: Option<usize>,
+    count: usize,
+    bonus: f64,
+    run_bonus: f64,
+}
 
-    let mut current_bounds: Option<(usize, usize)> = None;
-    let smallest_possible_match = query.len();
-    for &i in &indices {
-        if let Some(last_index) = find_end_of_match(string, rest, i) {
-            let last_bounds = Some((i, last_index));
-            let last_match_len = get_match_length(last_bounds).unwrap();
-            if current_bounds.is_none()
-                || last_match_len < get_match_length(current_bounds).unwrap()
-            {
-                current_bounds = last_bounds;
-                if last_match_len == smallest_possible_match {
-                    break;
-                }
+impl<'a, C: Copy + Into<char>> MatchScorer<'a, C> {
+    fn new(choice: &'a [C], basename_start: usize) -> MatchScorer<'a, C> {
+        MatchScorer {
+            choice,
+            basename_start,
+            first: 0,
+            last: None,
+            count: 0,
+            bonus: 0.0,
+            run_bonus: 0.0,
+        }
+    }
+
+    // Records the next matched character, which must come after every previous one.
+    fn push(&mut self, idx: usize) {
+        let boundary_bonus = boundary_bonus(self.choice, idx);
+        match self.last {
+            None => {
+                self.first = idx;
+                self.run_bonus = boundary_bonus;
             }
+            Some(last) if last + 1 != idx => self.run_bonus = boundary_bonus,
+            Some(_) => {}
+        }
+        self.bonus += self.run_bonus.max(boundary_bonus);
+        if idx >= self.basename_start {
+            self.bonus += BASENAME_BONUS;
         }
+        self.count += 1;
+        self.last = Some(idx);
+    }
+
+    fn score(&self) -> f64 {
+        let span = self.last.unwrap() - self.first + 1;
+        let ratio = self.count as f64 / span as f64;
+        ratio * (1.0 + self.bonus / self.count as f64)
     }
-    current_bounds
 }
 
-fn get_match_length(bounds: Option<(usize, usize)>) -> Option<usize> {
-    if let Some((lb, ub)) = bounds {
-        Some(ub - lb + 1)
+fn boundary_bonus<C: Copy + Into<char>>(choice: &[C], idx: usize) -> f64 {
+    if idx == 0 {
+        return BOUNDARY_BONUS;
+    }
+    let (prev, c): (char, char) = (choice[idx - 1].into(), choice[idx].into());
+    if is_separator(prev) || matches!(prev, '_' | '-' | '.' | ' ') {
+        BOUNDARY_BONUS
+    } else if prev.is_lowercase() && c.is_uppercase() {
+        CAMEL_CASE_BONUS
     } else {
-        None
+        0.0
     }
 }
 
-fn find_char_in_string(string: &[char], char: char) -> Vec<usize> {
-    let mut indices = Vec::new();
-    for (i, c) in string.iter().enumerate() {
-        if chars_equal(char, *c) {
-            indices.push(i);
-        }
+// Returns the index of the first character of the last component of a path.
+pub fn basename_start<C: Copy + Into<char>>(choice: &[C]) -> usize {
+    choice
+        .iter()
+        .rposition(|&c| is_separator(c.into()))
+        .map_or(0, |i| i + 1)
+}
+
+fn is_separator(c: char) -> bool {
+    c == '/' || c == '\\'
+}
+
+fn filter(choice: &Choice, query: &Query) -> f64 {
+    if query.is_empty() {
+        return 1.0;
+    }
+
+    let is_match = with_chars!(choice, |chars| {
+        query
+            .terms
+            .iter()
+            .all(|term| term.is_match(chars, query.case))
+    });
+    if is_match { 1.0 } else { 0.0 }
+}
+
+// This function is for picking out the matching characters for a given (choice, query) pair for
+// rendering purposes. It assumes that the given choice is in fact a match for the given query. The
+// indices returned are indices into the displayed text of the choice.
+pub fn visual_score(choice: &Choice, query: &str, case: CaseMode) -> Vec<usize> {
+    let query = Query::parse(query, case);
+    if query.is_empty() || choice.key().is_empty() {
+        return Vec::new();
+    }
+
+    let mut indices: Vec<usize> = with_chars!(choice, |chars| {
+        query
+            .terms
+            .iter()
+            .filter(|term| !term.negated)
+            .flat_map(|term| term.indices(chars, case))
+            .collect()
+    });
+    indices.sort_unstable();
+    indices.dedup();
+    for idx in &mut indices {
+        *idx = choice.display_index(*idx);
     }
     indices
 }
 
-fn find_end_of_match(string: &[char], rest_of_query: &[char], first_index: usize) -> Option<usize> {
-    get_match_indices(string, rest_of_query, first_index).map(|indices| indices[indices.len() - 1])
+fn find_end_of_match<C: Copy + Into<char>>(
+    string: &[C],
+    rest_of_query: &[char],
+    first_index: usize,
+    case: CaseMode,
+) -> Option<usize> {
+    let mut last_index = first_index;
+    if walk_match(string, rest_of_query, first_index, case, |idx| {
+        last_index = idx
+    }) {
+        Some(last_index)
+    } else {
+        None
+    }
 }
 
-fn get_match_indices(
-    string: &[char],
+fn get_match_indices<C: Copy + Into<char>>(
+    string: &[C],
     rest_of_query: &[char],
     first_index: usize,
+    case: CaseMode,
 ) -> Option<Vec<usize>> {
-    let mut ret = Vec::new();
-    let mut last_index = first_index + 1;
-    ret.push(first_index);
-    for c in rest_of_query {
-        let current_substring = &string[last_index..];
-        let mut index = None;
-        for (i, x) in current_substring.iter().enumerate() {
-            if chars_equal(*c, *x) {
-                index = Some(i);
-                break;
+    let mut ret = vec![first_index];
+    if walk_match(string, rest_of_query, first_index, case, |idx| {
+        ret.push(idx)
+    }) {
+        Some(ret)
+    } else {
+        None
+    }
+}
+
+// Greedily matches the rest of the query against the string, starting just after `first_index`,
+// and calls `visit` with the index of each matched character. Returns false if the rest of the
+// query couldn't be matched.
+fn walk_match<C: Copy + Into<char>>(
+    string: &[C],
+    rest_of_query: &[char],
+    first_index: usize,
+    case: CaseMode,
+    mut visit: impl FnMut(usize),
+) -> bool {
+    let mut next_index = first_index + 1;
+    for &q in rest_of_query {
+        let found = string[next_index..]
+            .iter()
+            .position(|&c| chars_equal(q, c.into(), case));
+        match found {
+            Some(idx) => {
+                next_index += idx + 1;
+                visit(next_index - 1);
             }
+            None => return false,
         }
-        if let Some(idx) = index {
-            last_index += idx + 1;
-            ret.push(last_index - 1);
-        } else {
-            return None;
-        }
     }
-    Some(ret)
+    true
+}
+
+fn find_substring<C: Copy + Into<char>>(
+    string: &[C],
+    substring: &[char],
+    case: CaseMode,
+) -> Option<usize> {
+    (0..=string.len() - substring.len()).find(|&i| substring_at(string, substring, i, case))
+}
+
+fn substring_at<C: Copy + Into<char>>(
+    string: &[C],
+    substring: &[char],
+    start: usize,
+    case: CaseMode,
+) -> bool {
+    string[start..start + substring.len()]
+        .iter()
+        .zip(substring)
+        .all(|(&c, &q)| chars_equal(q, c.into(), case))
+}
+
+fn chars_equal(q: char, c: char, case: CaseMode) -> bool {
+    match case {
+        CaseMode::Respect => q == c,
+        CaseMode::Ignore => q == c || fold_case(q) == fold_case(c),
+        CaseMode::Smart => q == c || (!q.is_uppercase() && fold_case(q) == fold_case(c)),
+    }
 }
 
-fn chars_equal(q: char, c: char) -> bool {
-    q == c || q == c.to_ascii_lowercase()
+// Simple (one-to-one) Unicode case folding. Characters whose lowercase form expands to more than
+// one character, such as 'İ', are left as they are.
+fn fold_case(c: char) -> char {
+    if c.is_ascii() {
+        return c.to_ascii_lowercase();
+    }
+    match c {
+        'ς' => 'σ',
+        'ϐ' => 'β',
+        'ϑ' => 'θ',
+        'ϕ' => 'φ',
+        'ϖ' => 'π',
+        'ϰ' => 'κ',
+        'ϱ' => 'ρ',
+        'ϵ' => 'ε',
+        'ſ' => 's',
+        _ => {
+            let mut lower = c.to_lowercase();
+            match (lower.next(), lower.next()) {
+                (Some(l), None) => l,
+                _ => c,
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{chars_equal, compute_matches, find_end_of_match, get_match_indices, score};
+    use super::CaseMode::{self, Ignore, Respect, Smart};
+    use super::{
+        BASENAME_BONUS, BOUNDARY_BONUS, Choice, Matches, Query, basename_start, compute_matches,
+    };
+
+    // The score of a choice that the query matches in its entirety, before adjusting for length.
+    const PERFECT: f64 = 1.0 + BOUNDARY_BONUS + BASENAME_BONUS;
+
+    fn assert_score(choice: &str, query: &str, expected: f64) {
+        let actual = score(choice, query);
+        assert!(
+            (actual - expected).abs() < 1e-12,
+            "score({choice:?}, {query:?}) was {actual}, expected {expected}"
+        );
+    }
+
+    fn choice(s: &str) -> Choice {
+        Choice::new(s.to_string())
+    }
+
+    fn choices(strs: &[&str]) -> Vec<Choice> {
+        strs.iter().map(|s| choice(s)).collect()
+    }
+
+    fn score(choice: &str, query: &str) -> f64 {
+        super::score(&self::choice(choice), &Query::parse(query, Smart))
+    }
+
+    fn matches(choice: &str, query: &str) -> bool {
+        let filtered = super::filter(&self::choice(choice), &Query::parse(query, Smart)) > 0.0;
+        assert_eq!(filtered, score(choice, query) > 0.0);
+        filtered
+    }
+
+    fn ranked(mut matches: Matches) -> Vec<usize> {
+        matches.sort_to(matches.len());
+        (0..matches.len())
+            .map(|i| matches.get(i).unwrap())
+            .collect()
+    }
+
+    fn matches_with(choice: &str, query: &str, case: CaseMode) -> bool {
+        super::filter(&self::choice(choice), &Query::parse(query, case)) > 0.0
+    }
+
+    fn chars_equal(q: char, c: char) -> bool {
+        super::chars_equal(q, c, Smart)
+    }
+
+    fn get_match_indices(string: &[char], rest: &[char], first: usize) -> Option<Vec<usize>> {
+        super::get_match_indices(string, rest, first, Smart)
+    }
+
+    fn find_end_of_match(string: &[char], rest: &[char], first: usize) -> Option<usize> {
+        super::find_end_of_match(string, rest, first, Smart)
+    }
+
+    fn visual_score(choice: &str, query: &str) -> Vec<usize> {
+        super::visual_score(&self::choice(choice), query, Smart)
+    }
+
+    fn narrows(previous: &str, query: &str) -> bool {
+        super::narrows(previous, query, Smart)
+    }
 
     #[test]
     fn chars_equal_test() {
@@ -200,6 +816,34 @@ mod tests {
         assert!(chars_equal('a', 'A'));
     }
 
+    #[test]
+    fn case_modes() {
+        assert!(matches_with("FooBar", "foobar", Smart));
+        assert!(matches_with("FooBar", "fooBar", Smart));
+        assert!(!matches_with("FooBar", "FOOBAR", Smart));
+
+        assert!(matches_with("FooBar", "foobar", Ignore));
+        assert!(matches_with("FooBar", "FOOBAR", Ignore));
+        assert!(matches_with("FooBar", "'OOB", Ignore));
+
+        assert!(matches_with("FooBar", "FooBar", Respect));
+        assert!(!matches_with("FooBar", "foobar", Respect));
+        assert!(!matches_with("FooBar", "^foo", Respect));
+    }
+
+    #[test]
+    fn unicode_case_folding() {
+        assert!(matches_with("CAFÉ.txt", "café", Smart));
+        assert!(!matches_with("café.txt", "CAFÉ", Smart));
+        assert!(matches_with("café.txt", "CAFÉ", Ignore));
+        assert!(!matches_with("CAFÉ.txt", "café", Respect));
+
+        assert!(matches_with("Документы/Отчёт.pdf", "отчёт", Smart));
+        assert!(matches_with("ΟΔΥΣΣΕΥΣ", "οδυσσευς", Smart));
+        assert!(matches_with("οδυσσευς", "ΟΔΥΣΣΕΥΣ", Ignore));
+        assert!(matches_with("λόγος", "λόγοσ", Smart));
+    }
+
     #[test]
     fn get_match_indices_test() {
         assert_eq!(
@@ -264,15 +908,17 @@ mod tests {
         assert!(score("bab", "a") > 0.0);
         assert!(score("babababab", "aaaa") > 0.0);
 
-        assert_eq!(score("a", "a"), 1_f64 / "a".len() as f64);
-        assert_eq!(score("ab", "ab"), 0.5);
-        assert_eq!(
-            score("a long string", "a long string"),
-            1_f64 / "a long string".len() as f64
+        assert_score("a", "a", PERFECT / "a".len() as f64);
+        assert_score("ab", "ab", PERFECT / 2.0);
+        assert_score(
+            "a long string",
+            "a long string",
+            PERFECT / "a long string".len() as f64,
         );
-        assert_eq!(
-            score("spec/search_spec.rb", "sear"),
-            1_f64 / "spec/search_spec.rb".len() as f64
+        assert_score(
+            "spec/search_spec.rb",
+            "sear",
+            PERFECT / "spec/search_spec.rb".len() as f64,
         );
     }
 
@@ -281,8 +927,8 @@ mod tests {
         assert!(score("/! symbols $^", "/!$^") > 0.0);
 
         assert_eq!(score("a", "A"), 0.0);
-        assert_eq!(score("A", "a"), 1.0);
-        assert_eq!(score("A", "A"), 1.0);
+        assert_score("A", "a", PERFECT);
+        assert_score("A", "A", PERFECT);
 
         assert_eq!(score("a", "aa"), 0.0);
     }
@@ -300,23 +946,179 @@ mod tests {
 
         assert!(score("long 12 long", "12") > score("1 long 2", "12"));
 
-        assert_eq!(score("121padding2", "12"), 1.0 / "121padding2".len() as f64);
-        assert_eq!(score("1padding212", "12"), 1.0 / "1padding212".len() as f64);
+        assert_score("121padding2", "12", PERFECT / "121padding2".len() as f64);
+        assert_score(
+            "1padding212",
+            "12",
+            (1.0 + BASENAME_BONUS) / "1padding212".len() as f64,
+        );
+
+        // Matches at word boundaries beat matches in the middle of a word.
+        assert!(score("foo_bar", "fb") > score("fooxbar", "fb"));
+        assert!(score("foo-bar", "fb") > score("fooxbar", "fb"));
+        assert!(score("foo.bar", "fb") > score("fooxbar", "fb"));
+        assert!(score("foo bar", "fb") > score("fooxbar", "fb"));
+        assert!(score("foo/bar", "fb") > score("fooxbar", "fb"));
+        assert!(score("FooBar", "fb") > score("Foobar", "fb"));
+        assert!(score("x_search", "search") > score("xxsearch", "search"));
+
+        // Matches in the basename beat matches in the directory.
+        assert!(score("src/main.rs", "main") > score("main/src.rs", "main"));
+        assert!(score("app/index/search.rb", "search") > score("app/search/index.rb", "search"));
+        assert!(score("a/b/c/search_spec.rb", "sear") > score("a/search/c/spec.rb", "sear"));
+
+        // Gaps are still penalized, even when the gapped match lands on a boundary.
+        assert!(score("ab_xx", "ab") > score("a_bxx", "ab"));
+        assert!(score("src/matching.rs", "match") > score("src/m_a_t_c_h.rs", "match"));
     }
 
     #[test]
     fn compute_matches_filter_only_preserves_order() {
-        let choices = vec!["barbarbar", "bar", "baz"];
-        let references = choices.iter().map(|s| *s).collect::<Vec<_>>();
-        let result = compute_matches(&references, "bar", true);
-        assert_eq!(result, vec!["barbarbar", "bar"]);
+        let choices = choices(&["barbarbar", "bar", "baz"]);
+        let result = ranked(compute_matches(&choices, &[0, 1, 2], "bar", Smart, true));
+        assert_eq!(result, vec![0, 1]);
     }
 
     #[test]
     fn compute_matches_ranks_by_score() {
-        let choices = vec!["barbarbar", "bar", "baz"];
-        let references = choices.iter().map(|s| *s).collect::<Vec<_>>();
-        let result = compute_matches(&references, "bar", false);
-        assert_eq!(result, vec!["bar", "barbarbar"]);
+        let choices = choices(&["barbarbar", "bar", "baz"]);
+        let result = ranked(compute_matches(&choices, &[0, 1, 2], "bar", Smart, false));
+        assert_eq!(result, vec![1, 0]);
+    }
+
+    #[test]
+    fn compute_matches_only_considers_candidates() {
+        let choices = choices(&["bar", "baz", "barbarbar", "bar"]);
+        let result = ranked(compute_matches(&choices, &[2, 3], "bar", Smart, false));
+        assert_eq!(result, vec![3, 2]);
+    }
+
+    #[test]
+    fn matches_are_ranked_lazily() {
+        let choices: Vec<Choice> = (0..1000)
+            .map(|i| Choice::new(format!("{}/{i}", "x".repeat(i % 37))))
+            .collect();
+        let candidates: Vec<usize> = (0..choices.len()).rev().collect();
+        let expected = ranked(compute_matches(&choices, &candidates, "x1", Smart, false));
+
+        let mut matches = compute_matches(&choices, &candidates, "x1", Smart, false);
+        assert_eq!(matches.len(), expected.len());
+        for n in [1, 20, 21, 60, expected.len()] {
+            matches.sort_to(n);
+            let top: Vec<usize> = (0..n).map(|i| matches.get(i).unwrap()).collect();
+            assert_eq!(top, expected[..n]);
+        }
+        assert_eq!(matches.get(expected.len()), None);
+    }
+
+    #[test]
+    fn query_parsing() {
+        assert_eq!(Query::parse("", Smart).terms.len(), 0);
+        assert_eq!(Query::parse("  ", Smart).terms.len(), 0);
+        assert_eq!(Query::parse("foo bar", Smart).terms.len(), 2);
+        assert_eq!(Query::parse("foo\\ bar", Smart).terms.len(), 1);
+        assert_eq!(Query::parse("foo ! ^ $ '", Smart).terms.len(), 1);
+    }
+
+    #[test]
+    fn extended_search_terms() {
+        assert!(matches("src/main.rs", "src rs"));
+        assert!(matches("src/main.rs", "rs src"));
+        assert!(!matches("src/main.rs", "src py"));
+        assert!(matches("foo bar", "foo\\ bar"));
+        assert!(!matches("foobar", "foo\\ bar"));
+
+        assert!(matches("src/main.rs", "'main"));
+        assert!(!matches("src/main.rs", "'mn"));
+        assert!(matches("src/main.rs", "mn"));
+
+        assert!(matches("src/main.rs", "^src"));
+        assert!(!matches("src/main.rs", "^main"));
+        assert!(matches("src/main.rs", ".rs$"));
+        assert!(!matches("src/main.rs", "main$"));
+        assert!(matches("src/main.rs", "^src/main.rs$"));
+        assert!(!matches("src/main.rs", "^src/main$"));
+
+        assert!(matches("src/main.rs", "!test"));
+        assert!(!matches("src/main.rs", "!main"));
+        assert!(matches("src/main.rs", "!mn"));
+        assert!(!matches("src/main.rs", "!^src"));
+        assert!(matches("src/main.rs", "!^main"));
+        assert!(!matches("src/main.rs", "!.rs$"));
+        assert!(matches("", "!foo"));
+        assert!(matches("src/main.rs", "src !test .rs$"));
+        assert!(!matches("src/main.rs", "src !main .rs$"));
+
+        assert!(matches("README", "'read"));
+        assert!(!matches("readme", "'READ"));
+    }
+
+    #[test]
+    fn extended_search_scoring() {
+        assert_eq!(
+            score("src/main.rs", "src"),
+            score("src/main.rs", "src !test")
+        );
+        assert!(score("src/main.rs", "src main") > score("src/xmxaxixn.rs", "src main"));
+        assert!(score("abc", "!x") > 0.0);
+    }
+
+    #[test]
+    fn visual_score_covers_every_term() {
+        assert_eq!(visual_score("src/main.rs", "src rs"), vec![0, 1, 2, 9, 10]);
+        assert_eq!(
+            visual_score("src/main.rs", "'main .rs$"),
+            vec![4, 5, 6, 7, 8, 9, 10]
+        );
+        assert_eq!(visual_score("src/main.rs", "sr !test"), vec![0, 1]);
+        assert_eq!(visual_score("src/main.rs", "src s"), vec![0, 1, 2]);
+        assert_eq!(visual_score("src/main.rs", "!foo"), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn matching_restricted_to_a_key() {
+        // "12:main.rs", matched against just "main.rs".
+        let offsets = (3..10).collect();
+        let choice = Choice::with_fields(
+            "src/12:main.rs".to_string(),
+            Some("12:main.rs".to_string()),
+            Some(("main.rs".to_string(), offsets)),
+        );
+        let query = |query| Query::parse(query, Smart);
+        assert!(super::score(&choice, &query("main")) > 0.0);
+        assert_eq!(super::score(&choice, &query("src")), 0.0);
+        assert_eq!(super::score(&choice, &query("12")), 0.0);
+        assert!(super::score(&choice, &query("^main.rs$")) > 0.0);
+        assert_eq!(super::visual_score(&choice, "mrs", Smart), vec![3, 8, 9]);
+        assert_eq!(choice.as_str(), "src/12:main.rs");
+        assert_eq!(choice.display(), "12:main.rs");
+    }
+
+    #[test]
+    fn basenames() {
+        let start = |s: &str| basename_start(&s.chars().collect::<Vec<_>>());
+        assert_eq!(start("src/main.rs"), 4);
+        assert_eq!(start("src\\main.rs"), 4);
+        assert_eq!(start("main.rs"), 0);
+        assert_eq!(start("é/ö"), 2);
+    }
+
+    #[test]
+    fn narrowing() {
+        assert!(narrows("", "a"));
+        assert!(narrows("fo", "foo"));
+        assert!(narrows("foo", "foo "));
+        assert!(narrows("foo ", "foo b"));
+        assert!(narrows("foo", "foo$"));
+        assert!(narrows("foo", "foo !"));
+        assert!(narrows("foo !", "foo !b"));
+        assert!(narrows("'fo", "'foo"));
+        assert!(narrows("^fo", "^foo"));
+        assert!(narrows("^foo", "^foo$"));
+
+        assert!(!narrows("!b", "!ba"));
+        assert!(!narrows("foo$", "foo$x"));
+        assert!(!narrows("^foo$", "^foo$x"));
+        assert!(!narrows("foo\\", "foo\\ "));
     }
 }
@@ -0,0 +1,375 @@
+use crate::ansi::{self, Token};
+use std::cmp::min;
+use std::io::{self, BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use unicode_width::UnicodeWidthChar;
+
+// How long the highlighted choice has to stay put before its preview command is run, so that
+// scrolling quickly through the matches doesn't run a command for every one of them.
+const PREVIEW_DELAY: Duration = Duration::from_millis(50);
+
+// Output beyond this many lines is discarded, and the command is killed.
+const MAX_PREVIEW_LINES: usize = 10_000;
+
+// Lines are cut off after this many bytes, so that a command which prints a huge file without
+// newlines can't use up all the memory. This is far more than fits in a pane, even with colors.
+const MAX_PREVIEW_LINE_BYTES: usize = 16 * 1024;
+
+const TAB_WIDTH: usize = 8;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, clap::ValueEnum)]
+pub enum PreviewPosition {
+    Right,
+    Bottom,
+}
+
+// Runs the preview command for the highlighted choice on a background thread, and keeps track of
+// the output of the most recent command along with how far it has been scrolled.
+pub struct Preview {
+    requests: Sender<String>,
+    results: Receiver<(String, Vec<String>)>,
+    // The command that is currently running, if any, so that it can be killed once its output is
+    // no longer wanted.
+    running: Arc<Mutex<Option<Process>>>,
+    // The choice whose preview was most recently requested.
+    target: Option<String>,
+    // The choice whose preview is currently displayed, and the output of its command.
+    shown: Option<String>,
+    lines: Vec<String>,
+    scroll_offset: usize,
+}
+
+impl Preview {
+    pub fn spawn(command: String) -> Preview {
+        let (requests_tx, requests_rx) = mpsc::channel();
+        let (results_tx, results_rx) = mpsc::channel();
+        let running = Arc::new(Mutex::new(None));
+        let running_clone = running.clone();
+        thread::spawn(move || run_previews(&command, &requests_rx, &results_tx, &running_clone));
+        Preview {
+            requests: requests_tx,
+            results: results_rx,
+            running,
+            target: None,
+            shown: None,
+            lines: Vec::new(),
+            scroll_offset: 0,
+        }
+    }
+
+    // Requests a preview of the given choice, unless it's the one that's already being shown.
+    pub fn show(&mut self, choice: Option<&str>) {
+        if self.target.as_deref() == choice {
+            return;
+        }
+        self.target = choice.map(str::to_string);
+        if let Some(process) = self.running.lock().unwrap().as_mut() {
+            process.kill();
+        }
+        match choice {
+            Some(choice) => self.requests.send(choice.to_string()).unwrap(),
+            None => {
+                self.shown = None;
+                self.lines.clear();
+            }
+        }
+    }
+
+    // Picks up the output of any commands that have finished since the last call.
+    pub fn update(&mut self) {
+        while let Ok((choice, lines)) = self.results.try_recv() {
+            if self.target.as_ref() == Some(&choice) {
+                self.shown = Some(choice);
+                self.lines = lines;
+                self.scroll_offset = 0;
+            }
+        }
+    }
+
+    // Returns true while the preview of the highlighted choice hasn't arrived yet.
+    pub fn is_loading(&self) -> bool {
+        self.target != self.shown
+    }
+
+    pub fn scroll(&mut self, lines: isize, height: u16) {
+        let max = self.lines.len().saturating_sub(height as usize);
+        self.scroll_offset = self.scroll_offset.saturating_add_signed(lines).min(max);
+    }
+
+    // Returns the given number of lines of output, starting from the current scroll position.
+    pub fn visible_lines(&self, height: u16) -> &[String] {
+        let start = min(self.scroll_offset, self.lines.len());
+        let end = min(start + height as usize, self.lines.len());
+        &self.lines[start..end]
+    }
+}
+
+impl Drop for Preview {
+    fn drop(&mut self) {
+        if let Some(process) = self.running.lock().unwrap().as_mut() {
+            process.kill();
+        }
+    }
+}
+
+fn run_previews(
+    command: &str,
+    requests: &Receiver<String>,
+    results: &Sender<(String, Vec<String>)>,
+    running: &Mutex<Option<Process>>,
+) {
+    while let Ok(mut choice) = requests.recv() {
+        loop {
+            match requests.recv_timeout(PREVIEW_DELAY) {
+                Ok(newer) => choice = newer,
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+        let lines = run_command(command, &choice, running);
+        if results.send((choice, lines)).is_err() {
+            return;
+        }
+    }
+}
+
+fn run_command(command: &str, choice: &str, running: &Mutex<Option<Process>>) -> Vec<String> {
+    let command = command.replace("{}", &quote(choice));
+    let mut process = match Process::spawn(&command) {
+        Ok(process) => process,
+        Err(e) => return vec![format!("Failed to run preview command: {e}")],
+    };
+    let stdout = process.child.stdout.take().unwrap();
+    *running.lock().unwrap() = Some(process);
+
+    let mut lines = Vec::new();
+    let mut reader = BufReader::new(stdout);
+    let mut line = Vec::new();
+    while let Ok(true) = read_line(&mut reader, &mut line) {
+        if line.ends_with(b"\r") {
+            line.pop();
+        }
+        lines.push(String::from_utf8_lossy(&line).into_owned());
+        if lines.len() == MAX_PREVIEW_LINES {
+            break;
+        }
+    }
+
+    if let Some(mut process) = running.lock().unwrap().take() {
+        process.kill();
+        let _ = process.child.wait();
+    }
+    lines
+}
+
+// Reads the next line of output into `line`, without the newline, keeping at most
+// MAX_PREVIEW_LINE_BYTES of it. Returns false once there's no more output.
+fn read_line(reader: &mut impl BufRead, line: &mut Vec<u8>) -> io::Result<bool> {
+    line.clear();
+    let mut read_any = false;
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(read_any);
+        }
+        read_any = true;
+        let newline = buf.iter().position(|&b| b == b'\n');
+        let end = newline.unwrap_or(buf.len());
+        let room = MAX_PREVIEW_LINE_BYTES.saturating_sub(line.len());
+        line.extend_from_slice(&buf[..min(end, room)]);
+        reader.consume(newline.map_or(end, |end| end + 1));
+        if newline.is_some() {
+            return Ok(true);
+        }
+    }
+}
+
+// A running preview command. Killing it also kills any processes that it started, which would
+// otherwise keep running (and writing to the pipe) after the shell is gone.
+struct Process {
+    child: Child,
+    // The job object that the command was assigned to, if one could be created.
+    #[cfg(windows)]
+    job: Option<std::os::windows::io::OwnedHandle>,
+}
+
+impl Process {
+    #[cfg(not(windows))]
+    fn spawn(command: &str) -> io::Result<Process> {
+        use std::os::unix::process::CommandExt;
+        let child = shell_command(command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .process_group(0)
+            .spawn()?;
+        Ok(Process { child })
+    }
+
+    #[cfg(windows)]
+    fn spawn(command: &str) -> io::Result<Process> {
+        use std::os::windows::io::{AsRawHandle, FromRawHandle, OwnedHandle};
+        use windows::Win32::Foundation::HANDLE;
+        use windows::Win32::System::JobObjects::{AssignProcessToJobObject, CreateJobObjectW};
+        use windows::core::PCWSTR;
+
+        let child = shell_command(command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let job = unsafe { CreateJobObjectW(None, PCWSTR::null()) }
+            .ok()
+            .map(|job| unsafe { OwnedHandle::from_raw_handle(job.0) })
+            .filter(|job| unsafe {
+                let process = HANDLE(child.as_raw_handle());
+                AssignProcessToJobObject(HANDLE(job.as_raw_handle()), process).is_ok()
+            });
+        Ok(Process { child, job })
+    }
+
+    // The command was made the leader of a new process group, which its children join.
+    #[cfg(not(windows))]
+    fn kill(&mut self) {
+        if let Ok(pid) = libc::pid_t::try_from(self.child.id()) {
+            unsafe { libc::kill(-pid, libc::SIGKILL) };
+        }
+    }
+
+    #[cfg(windows)]
+    fn kill(&mut self) {
+        use std::os::windows::io::AsRawHandle;
+        use windows::Win32::Foundation::HANDLE;
+        use windows::Win32::System::JobObjects::TerminateJobObject;
+
+        match &self.job {
+            Some(job) => {
+                let _ = unsafe { TerminateJobObject(HANDLE(job.as_raw_handle()), 1) };
+            }
+            None => {
+                let _ = self.child.kill();
+            }
+        }
+    }
+}
+
+#[cfg(not(windows))]
+fn shell_command(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+fn shell_command(command: &str) -> Command {
+    use std::os::windows::process::CommandExt;
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").raw_arg(command);
+    shell
+}
+
+#[cfg(not(windows))]
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+// cmd expands %VAR% even inside quotes, so percent signs are taken out of the quotes and escaped
+// with a caret, which also keeps cmd from finding a variable name around them.
+#[cfg(windows)]
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "\"\"").replace('%', "\"^%\""))
+}
+
+// Prepares a line of command output for display in a pane of the given width. Color and style
+// escape sequences are passed through, but any other control sequences (which could move the
+// cursor and corrupt the display) are dropped, and tabs are expanded.
+pub fn render_line(line: &str, width: usize) -> String {
+    let mut ret = String::new();
+    let mut used = 0;
+    let mut styled = false;
+    for token in ansi::tokenize(line) {
+        match token {
+            Token::Sgr(sequence) => {
+                ret.push_str(sequence);
+                styled = true;
+            }
+            Token::Escape(_) => {}
+            Token::Char('\t') => {
+                let spaces = min(TAB_WIDTH - used % TAB_WIDTH, width - used);
+                ret.extend(std::iter::repeat_n(' ', spaces));
+                used += spaces;
+            }
+            Token::Char(c) if !c.is_control() => {
+                let c_width = c.width().unwrap_or(0);
+                if used + c_width > width {
+                    break;
+                }
+                ret.push(c);
+                used += c_width;
+            }
+            Token::Char(_) => {}
+        }
+        if used == width {
+            break;
+        }
+    }
+    if styled {
+        ret.push_str("\x1b[0m");
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MAX_PREVIEW_LINE_BYTES, read_line, render_line};
+    use std::io::BufReader;
+
+    #[test]
+    fn read_line_caps_long_lines() {
+        let long = "x".repeat(MAX_PREVIEW_LINE_BYTES + 10);
+        let input = format!("a\n{long}\nb");
+        // A small buffer, so that the long line takes several reads.
+        let mut reader = BufReader::with_capacity(7, input.as_bytes());
+        let mut line = Vec::new();
+        let mut lines = Vec::new();
+        while read_line(&mut reader, &mut line).unwrap() {
+            lines.push(String::from_utf8(line.clone()).unwrap());
+        }
+        assert_eq!(lines, ["a", &long[..MAX_PREVIEW_LINE_BYTES], "b"]);
+    }
+
+    #[test]
+    fn render_line_truncates_to_width() {
+        assert_eq!(render_line("hello", 10), "hello");
+        assert_eq!(render_line("hello", 3), "hel");
+        assert_eq!(render_line("日本語", 5), "日本");
+        assert_eq!(render_line("", 3), "");
+    }
+
+    #[test]
+    fn render_line_expands_tabs() {
+        assert_eq!(render_line("a\tb", 20), "a       b");
+        assert_eq!(render_line("a\tb", 4), "a   ");
+    }
+
+    #[test]
+    fn render_line_passes_colors_through() {
+        assert_eq!(
+            render_line("\x1b[31mred\x1b[0m plain", 20),
+            "\x1b[31mred\x1b[0m plain\x1b[0m"
+        );
+        assert_eq!(render_line("\x1b[1;32mgreen", 3), "\x1b[1;32mgre\x1b[0m");
+    }
+
+    #[test]
+    fn render_line_drops_other_escape_sequences() {
+        assert_eq!(render_line("a\x1b[2Jb\x1b[10;1Hc", 10), "abc");
+        assert_eq!(render_line("\x1b]0;title\x07text", 10), "text");
+        assert_eq!(render_line("\x1b]8;;http://x\x1b\\link", 10), "link");
+        assert_eq!(render_line("a\rb\x08c", 10), "abc");
+    }
+}
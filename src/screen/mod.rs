@@ -1,33 +1,160 @@
-use crate::ansi;
-use std::cmp::min;
+use crate::theme::TextStyle;
+use crate::{NEWLINE, ansi};
+use Key::*;
+use std::cmp::{max, min};
+use std::time::Duration;
 #[cfg(not(windows))]
 use unix::UnixScreen;
 #[cfg(windows)]
 pub use windows::WindowsScreen;
 
-#[derive(Debug, PartialEq, Copy, Clone)]
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum Key {
     Char(char),
     Control(char),
+    Alt(char),
     Enter,
+    AltEnter,
     Backspace,
     Tab,
     ShiftTab,
     Down,
     Up,
+    Left,
+    Right,
+    Delete,
     Home,
     End,
     PgDown,
     PgUp,
+    ShiftUp,
+    ShiftDown,
+    ShiftPgUp,
+    ShiftPgDown,
+    // Mouse events, along with the (0-based) row and column of the window where they happened.
+    Click { row: u16, col: u16 },
+    RightClick { row: u16, col: u16 },
+    WheelUp { row: u16, col: u16 },
+    WheelDown { row: u16, col: u16 },
     Resize,
 }
 
+// Parses a key name, such as `ctrl-v`, `alt-enter`, `pgdn` or `x`, as used on the command line.
+pub fn parse_key(name: &str) -> Result<Key, String> {
+    let lower = name.to_lowercase();
+    let single_char = |s: &str| {
+        let mut chars = s.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(c),
+            _ => None,
+        }
+    };
+    let key = match lower.as_str() {
+        "enter" | "return" => Enter,
+        "alt-enter" | "alt-return" => AltEnter,
+        "tab" => Tab,
+        "shift-tab" | "btab" => ShiftTab,
+        "esc" => Control('g'),
+        "bspace" | "backspace" => Backspace,
+        "up" => Up,
+        "down" => Down,
+        "left" => Left,
+        "right" => Right,
+        "del" | "delete" => Delete,
+        "home" => Home,
+        "end" => End,
+        "pgup" | "page-up" => PgUp,
+        "pgdn" | "page-down" => PgDown,
+        "shift-up" => ShiftUp,
+        "shift-down" => ShiftDown,
+        "shift-pgup" | "shift-page-up" => ShiftPgUp,
+        "shift-pgdn" | "shift-page-down" => ShiftPgDown,
+        _ => {
+            if let Some(c) = lower.strip_prefix("ctrl-").and_then(single_char) {
+                if !c.is_ascii_lowercase() {
+                    return Err(format!("unsupported key '{name}'"));
+                }
+                // These control characters are indistinguishable from their own keys.
+                match c {
+                    'i' => Tab,
+                    'm' => Enter,
+                    c => Control(c),
+                }
+            } else if let Some(c) = name.get(4..).filter(|_| lower.starts_with("alt-")) {
+                Alt(single_char(c).ok_or_else(|| format!("unsupported key '{name}'"))?)
+            } else if let Some(c) = single_char(name) {
+                Char(c)
+            } else {
+                return Err(format!("unknown key '{name}'"));
+            }
+        }
+    };
+    Ok(key)
+}
+
+// How many lines of the window to take up, including the prompt line.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Height {
+    Lines(u16),
+    // A percentage of the window's height, but no less than `min_lines` lines.
+    Percent { percent: u16, min_lines: u16 },
+}
+
+impl Height {
+    pub fn with_min_lines(self, min_lines: u16) -> Height {
+        match self {
+            Height::Percent { percent, .. } => Height::Percent { percent, min_lines },
+            lines => lines,
+        }
+    }
+
+    // Returns how many rows to show below the prompt line in a window with the given number of rows.
+    pub fn rows(self, window_rows: u16) -> u16 {
+        let lines = match self {
+            Height::Lines(lines) => lines,
+            Height::Percent { percent, min_lines } => {
+                let lines = u32::from(window_rows) * u32::from(percent) / 100;
+                max(lines as u16, min_lines)
+            }
+        };
+        min(lines, window_rows).saturating_sub(1)
+    }
+}
+
+// Parses a height, such as `15` or `40%`, as used on the command line.
+pub fn parse_height(s: &str) -> Result<Height, String> {
+    let invalid = || format!("invalid height '{s}'");
+    if let Some(percent) = s.strip_suffix('%') {
+        let percent = percent.parse().map_err(|_| invalid())?;
+        if percent == 0 || percent > 100 {
+            return Err(invalid());
+        }
+        Ok(Height::Percent {
+            percent,
+            min_lines: 0,
+        })
+    } else {
+        match s.parse() {
+            Ok(0) | Err(_) => Err(invalid()),
+            Ok(lines) => Ok(Height::Lines(lines)),
+        }
+    }
+}
+
 pub trait Screen {
     fn winsize(&self) -> Option<(u16, u16)>;
     fn write_bytes(&mut self, bytes: &[u8]);
     fn flush(&mut self);
     fn desired_rows(&self) -> u16;
 
+    // Clears the display after the window has been resized, and starts it over, leaving room for as
+    // many rows as fit now. Only the alternate screen is cleared entirely.
+    fn resize(&mut self);
+
+    // Returns the row of the window that the last row of the display is on, if it's known. Mouse
+    // events can only be matched up with what was drawn when it is.
+    fn bottom_row(&self) -> Option<u16>;
+
     fn rows(&self) -> u16 {
         let (_, rows) = self.winsize().unwrap();
         rows
@@ -65,6 +192,25 @@ pub trait Screen {
         self.reset_cursor_with_rows(visible_choices);
     }
 
+    // Starts the display over after a resize, leaving room for as many rows as fit now, and returns
+    // that number of rows. In inline mode, only the `reserved_rows` rows that the display took up
+    // (above the saved cursor position) are cleared, so that the output above them stays.
+    fn start_over(&mut self, alt_screen: bool, reserved_rows: u16) -> u16 {
+        if alt_screen {
+            self.write_bytes(ansi::blank_screen());
+            self.write_bytes(ansi::cursor_home());
+        } else {
+            self.reset_cursor_with_rows(reserved_rows);
+            self.write_bytes(ansi::clear_to_end_of_screen());
+        }
+        let visible_choices = self.visible_choices();
+        for _ in 0..visible_choices {
+            self.write(NEWLINE);
+        }
+        self.write_bytes(ansi::save_cursor());
+        visible_choices
+    }
+
     fn show_cursor(&mut self) {
         self.write_bytes(ansi::show_cursor());
         self.flush();
@@ -74,47 +220,83 @@ pub trait Screen {
         self.write_bytes(ansi::hide_cursor());
     }
 
-    fn blank_entire_screen(&mut self) {
-        self.write_bytes(ansi::blank_screen());
-    }
-
     fn write(&mut self, s: &str) {
         self.write_bytes(s.as_bytes());
     }
 
-    fn write_red_inverted(&mut self, s: &str) {
-        self.write_bytes(ansi::red());
-        self.write_bytes(ansi::inverse());
-        self.write(s);
-        self.write_bytes(ansi::reset());
-    }
-
-    fn write_red(&mut self, s: &str) {
-        self.write_bytes(ansi::red());
-        self.write(s);
-        self.write_bytes(ansi::reset());
-    }
-
-    fn write_inverted(&mut self, s: &str) {
-        self.write_bytes(ansi::inverse());
+    fn write_styled(&mut self, style: &TextStyle, s: &str) {
+        if style.is_plain() || s.is_empty() {
+            self.write(s);
+            return;
+        }
+        self.write_bytes(style.sgr().as_bytes());
         self.write(s);
         self.write_bytes(ansi::reset());
     }
 
-    fn get_buffered_keys(&mut self) -> Vec<Key>;
+    // Returns all buffered keystrokes, blocking until at least one key arrives. If a timeout is
+    // given and it elapses first, an empty vector is returned.
+    fn get_buffered_keys(&mut self, timeout: Option<Duration>) -> Vec<Key>;
 }
 
 #[cfg(windows)]
-pub fn new(desired_rows: u16) -> Box<dyn Screen> {
-    Box::from(WindowsScreen::open_screen(desired_rows))
+pub fn new(height: Height, mouse: bool, alt_screen: bool) -> Box<dyn Screen> {
+    Box::from(WindowsScreen::open_screen(height, mouse, alt_screen))
 }
 
 #[cfg(not(windows))]
-pub fn new(desired_rows: u16) -> Box<dyn Screen> {
-    Box::from(UnixScreen::open_screen(desired_rows))
+pub fn new(height: Height, mouse: bool, alt_screen: bool) -> Box<dyn Screen> {
+    Box::from(UnixScreen::open_screen(height, mouse, alt_screen))
 }
 
 #[cfg(not(windows))]
 mod unix;
 #[cfg(windows)]
 mod windows;
+
+#[cfg(test)]
+mod tests {
+    use super::Key::*;
+    use super::{Height, parse_height, parse_key};
+
+    #[test]
+    fn key_names() {
+        assert_eq!(parse_key("ctrl-v"), Ok(Control('v')));
+        assert_eq!(parse_key("CTRL-X"), Ok(Control('x')));
+        assert_eq!(parse_key("ctrl-m"), Ok(Enter));
+        assert_eq!(parse_key("alt-enter"), Ok(AltEnter));
+        assert_eq!(parse_key("alt-a"), Ok(Alt('a')));
+        assert_eq!(parse_key("alt-A"), Ok(Alt('A')));
+        assert_eq!(parse_key("pgdn"), Ok(PgDown));
+        assert_eq!(parse_key("esc"), Ok(Control('g')));
+        assert_eq!(parse_key("?"), Ok(Char('?')));
+        assert!(parse_key("ctrl-1").is_err());
+        assert!(parse_key("alt-xy").is_err());
+        assert!(parse_key("hyper-x").is_err());
+        assert!(parse_key("").is_err());
+    }
+
+    #[test]
+    fn heights() {
+        assert_eq!(parse_height("15"), Ok(Height::Lines(15)));
+        let forty_percent = parse_height("40%").unwrap().with_min_lines(10);
+        assert_eq!(
+            forty_percent,
+            Height::Percent {
+                percent: 40,
+                min_lines: 10
+            }
+        );
+        assert!(parse_height("0").is_err());
+        assert!(parse_height("101%").is_err());
+        assert!(parse_height("-5").is_err());
+        assert!(parse_height("tall").is_err());
+
+        // The prompt line takes up one of the lines.
+        assert_eq!(Height::Lines(15).rows(50), 14);
+        assert_eq!(Height::Lines(15).rows(10), 9);
+        assert_eq!(forty_percent.rows(50), 19);
+        assert_eq!(forty_percent.rows(20), 9);
+        assert_eq!(forty_percent.rows(5), 4);
+    }
+}
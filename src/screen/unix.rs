@@ -1,10 +1,9 @@
 #![cfg(not(windows))]
 
-use super::Key;
+use super::{Height, Key};
 use crate::NEWLINE;
 use crate::ansi;
 use crate::logging;
-use std::cmp::min;
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Write};
 use std::os::unix::io::*;
@@ -15,20 +14,47 @@ use std::str;
 
 use std::sync::mpsc;
 use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
 use std::sync::mpsc::Sender;
 use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::screen::Screen;
 use ::libc::{SIGINT, SIGWINCH, c_int, c_ulong, c_ushort, dup};
 
+// How long to wait for the terminal to report the cursor position.
+const CURSOR_POSITION_TIMEOUT: Duration = Duration::from_millis(200);
+
 pub struct UnixScreen {
     tty: Terminal,
-    desired_rows: u16,
+    height: Height,
+    mouse: bool,
+    alt_screen: bool,
+    bottom_row: Option<u16>,
+    reserved_rows: u16,
+    // Input that arrived while waiting for the cursor position, which hasn't been translated yet.
+    pending_input: Vec<u8>,
+    // Whether the terminal didn't report the cursor position in time, so the report may still
+    // arrive with the keys.
+    late_cursor_report: bool,
 }
 
 impl Screen for UnixScreen {
     fn desired_rows(&self) -> u16 {
-        self.desired_rows
+        self.height.rows(self.rows())
+    }
+
+    fn resize(&mut self) {
+        self.reserved_rows = self.start_over(self.alt_screen, self.reserved_rows);
+        if self.alt_screen {
+            self.bottom_row = self.bottom_row.map(|_| self.reserved_rows);
+        } else if self.bottom_row.is_some() {
+            self.bottom_row = self.read_cursor_row();
+        }
+    }
+
+    fn bottom_row(&self) -> Option<u16> {
+        self.bottom_row
     }
 
     fn winsize(&self) -> Option<(u16, u16)> {
@@ -44,8 +70,9 @@ impl Screen for UnixScreen {
     }
 
     // Return all buffered keystrokes, or the next key if buffer is empty.
-    fn get_buffered_keys(&mut self) -> Vec<Key> {
-        let mut ret = Vec::new();
+    fn get_buffered_keys(&mut self, timeout: Option<Duration>) -> Vec<Key> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut ret = std::mem::take(&mut self.pending_input);
         while let Ok(bytes) = self.tty.input.try_recv() {
             #[cfg(debug_assertions)]
             logging::log_line(&format!(
@@ -54,31 +81,101 @@ impl Screen for UnixScreen {
             ));
             ret.extend(bytes);
         }
+        self.strip_late_cursor_report(&mut ret);
         while ret.is_empty() {
-            let bytes = self.tty.input.recv().unwrap();
+            let bytes = match deadline {
+                None => self.tty.input.recv().unwrap(),
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    match self.tty.input.recv_timeout(remaining) {
+                        Ok(bytes) => bytes,
+                        Err(RecvTimeoutError::Timeout) => return Vec::new(),
+                        Err(e) => panic!("{e}"),
+                    }
+                }
+            };
             #[cfg(debug_assertions)]
             logging::log_line(&format!(
                 "[get_buffered_keys] blocking read got {} bytes",
                 bytes.len()
             ));
             ret.extend_from_slice(&bytes);
+            self.strip_late_cursor_report(&mut ret);
         }
         ansi::translate_bytes(&ret)
     }
 }
 
 impl UnixScreen {
-    pub fn open_screen(desired_rows: u16) -> UnixScreen {
+    pub fn open_screen(height: Height, mouse: bool, alt_screen: bool) -> UnixScreen {
         let mut tty = Terminal::open_terminal();
+        if alt_screen {
+            tty.write(ansi::enter_alternate_screen());
+        }
         tty.write(ansi::reset());
         let (_, rows) = tty.winsize().unwrap();
-        let visible_choices = min(desired_rows, rows - 1);
+        let visible_choices = height.rows(rows);
         for _ in 0..visible_choices {
             tty.write(NEWLINE.as_bytes());
         }
         tty.write(ansi::save_cursor());
 
-        UnixScreen { tty, desired_rows }
+        let mut screen = UnixScreen {
+            tty,
+            height,
+            mouse,
+            alt_screen,
+            bottom_row: None,
+            reserved_rows: visible_choices,
+            pending_input: Vec::new(),
+            late_cursor_report: false,
+        };
+        if mouse {
+            screen.tty.write(ansi::enable_mouse());
+            screen.bottom_row = screen.read_cursor_row();
+        }
+        screen
+    }
+
+    // Asks the terminal which row the cursor is on. Any other input that arrives in the meantime is
+    // kept, to be returned by the next call to `get_buffered_keys`.
+    fn read_cursor_row(&mut self) -> Option<u16> {
+        self.tty.write(ansi::request_cursor_position());
+        self.tty.flush();
+        let deadline = Instant::now() + CURSOR_POSITION_TIMEOUT;
+        loop {
+            if let Some((range, row)) = ansi::find_cursor_position_report(&self.pending_input) {
+                self.pending_input.drain(range);
+                return Some(row);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let Ok(bytes) = self.tty.input.recv_timeout(remaining) else {
+                self.late_cursor_report = true;
+                return None;
+            };
+            self.pending_input.extend(bytes);
+        }
+    }
+
+    // Removes the cursor position report that `read_cursor_row` gave up on, if it has arrived.
+    fn strip_late_cursor_report(&mut self, bytes: &mut Vec<u8>) {
+        if self.late_cursor_report
+            && let Some((range, _)) = ansi::find_cursor_position_report(bytes)
+        {
+            bytes.drain(range);
+            self.late_cursor_report = false;
+        }
+    }
+}
+
+impl Drop for UnixScreen {
+    fn drop(&mut self) {
+        if self.mouse {
+            self.tty.write(ansi::disable_mouse());
+        }
+        if self.alt_screen {
+            self.tty.write(ansi::leave_alternate_screen());
+        }
     }
 }
 
@@ -1,21 +1,25 @@
-use super::Key;
 use super::Key::*;
 use super::Screen;
+use super::{Height, Key};
 use crate::ansi;
 use crate::{NEWLINE, logging};
-use std::cmp::min;
 use std::str;
+use std::time::{Duration, Instant};
 
-use windows::Win32::Foundation::{HANDLE, INVALID_HANDLE_VALUE};
+use windows::Win32::Foundation::{HANDLE, INVALID_HANDLE_VALUE, WAIT_OBJECT_0};
 use windows::Win32::Storage::FileSystem::{
     CreateFileW, FILE_ATTRIBUTE_NORMAL, FILE_GENERIC_READ, FILE_GENERIC_WRITE, FILE_SHARE_READ,
     FILE_SHARE_WRITE, OPEN_EXISTING,
 };
 use windows::Win32::System::Console::{
-    CONSOLE_MODE, CONSOLE_SCREEN_BUFFER_INFO, ENABLE_VIRTUAL_TERMINAL_INPUT,
-    ENABLE_VIRTUAL_TERMINAL_PROCESSING, GetConsoleMode, GetConsoleScreenBufferInfo, INPUT_RECORD,
-    KEY_EVENT, ReadConsoleInputW, SetConsoleMode, WINDOW_BUFFER_SIZE_EVENT, WriteConsoleW,
+    CONSOLE_MODE, CONSOLE_SCREEN_BUFFER_INFO, COORD, DOUBLE_CLICK, ENABLE_EXTENDED_FLAGS,
+    ENABLE_MOUSE_INPUT, ENABLE_QUICK_EDIT_MODE, ENABLE_VIRTUAL_TERMINAL_INPUT,
+    ENABLE_VIRTUAL_TERMINAL_PROCESSING, FROM_LEFT_1ST_BUTTON_PRESSED, GetConsoleMode,
+    GetConsoleScreenBufferInfo, INPUT_RECORD, KEY_EVENT, MOUSE_EVENT, MOUSE_EVENT_RECORD,
+    MOUSE_WHEELED, RIGHTMOST_BUTTON_PRESSED, ReadConsoleInputW, SetConsoleMode,
+    WINDOW_BUFFER_SIZE_EVENT, WriteConsoleW,
 };
+use windows::Win32::System::Threading::WaitForSingleObject;
 use windows::core::w;
 
 macro_rules! win32 {
@@ -29,12 +33,29 @@ macro_rules! win32 {
 
 pub struct WindowsScreen {
     tty: Terminal,
-    desired_rows: u16,
+    height: Height,
+    alt_screen: bool,
+    bottom_row: Option<u16>,
+    reserved_rows: u16,
 }
 
 impl Screen for WindowsScreen {
     fn desired_rows(&self) -> u16 {
-        self.desired_rows
+        self.height.rows(self.rows())
+    }
+
+    fn resize(&mut self) {
+        self.reserved_rows = self.start_over(self.alt_screen, self.reserved_rows);
+        if self.alt_screen {
+            self.bottom_row = self.bottom_row.map(|_| self.reserved_rows);
+        } else if self.bottom_row.is_some() {
+            self.tty.flush();
+            self.bottom_row = self.tty.cursor_row();
+        }
+    }
+
+    fn bottom_row(&self) -> Option<u16> {
+        self.bottom_row
     }
 
     fn winsize(&self) -> Option<(u16, u16)> {
@@ -49,23 +70,42 @@ impl Screen for WindowsScreen {
         self.tty.flush();
     }
 
-    fn get_buffered_keys(&mut self) -> Vec<Key> {
-        self.tty.read_events()
+    fn get_buffered_keys(&mut self, timeout: Option<Duration>) -> Vec<Key> {
+        self.tty.read_events(timeout)
     }
 }
 
 impl WindowsScreen {
-    pub fn open_screen(desired_rows: u16) -> WindowsScreen {
-        let mut tty = Terminal::open_terminal();
+    pub fn open_screen(height: Height, mouse: bool, alt_screen: bool) -> WindowsScreen {
+        let mut tty = Terminal::open_terminal(mouse);
+        if alt_screen {
+            tty.write(ansi::enter_alternate_screen());
+        }
         tty.write(ansi::reset());
         let (_, rows) = tty.winsize().unwrap();
-        let visible_choices = min(desired_rows, rows.saturating_sub(1));
+        let visible_choices = height.rows(rows);
         for _ in 0..visible_choices {
             tty.write(NEWLINE.as_bytes());
         }
         tty.write(ansi::save_cursor());
+        tty.flush();
+        let bottom_row = if mouse { tty.cursor_row() } else { None };
 
-        WindowsScreen { tty, desired_rows }
+        WindowsScreen {
+            tty,
+            height,
+            alt_screen,
+            bottom_row,
+            reserved_rows: visible_choices,
+        }
+    }
+}
+
+impl Drop for WindowsScreen {
+    fn drop(&mut self) {
+        if self.alt_screen {
+            self.tty.write(ansi::leave_alternate_screen());
+        }
     }
 }
 
@@ -78,7 +118,7 @@ struct Terminal {
 }
 
 impl Terminal {
-    fn open_terminal() -> Terminal {
+    fn open_terminal(mouse: bool) -> Terminal {
         let rw_access = FILE_GENERIC_READ | FILE_GENERIC_WRITE;
         let conin;
         let conout;
@@ -118,7 +158,12 @@ impl Terminal {
         vt_output_mode |= ENABLE_VIRTUAL_TERMINAL_PROCESSING;
         win32!(SetConsoleMode(conout, vt_output_mode));
 
-        let vt_input_mode = input_mode | ENABLE_VIRTUAL_TERMINAL_INPUT;
+        let mut vt_input_mode = input_mode | ENABLE_VIRTUAL_TERMINAL_INPUT;
+        if mouse {
+            // Quick edit mode has to be turned off, or it would take over the mouse.
+            vt_input_mode |= ENABLE_MOUSE_INPUT | ENABLE_EXTENDED_FLAGS;
+            vt_input_mode &= !ENABLE_QUICK_EDIT_MODE;
+        }
         win32!(SetConsoleMode(conin, vt_input_mode));
 
         Terminal {
@@ -154,9 +199,44 @@ impl Terminal {
         console_winsize(self.conout)
     }
 
-    fn read_events(&mut self) -> Vec<Key> {
+    fn cursor_row(&self) -> Option<u16> {
+        let buffer_info = screen_buffer_info(self.conout)?;
+        let (row, _) = buffer_to_window(&buffer_info, buffer_info.dwCursorPosition)?;
+        Some(row)
+    }
+
+    fn mouse_key(&self, event: &MOUSE_EVENT_RECORD) -> Option<Key> {
+        let buffer_info = screen_buffer_info(self.conout)?;
+        let (row, col) = buffer_to_window(&buffer_info, event.dwMousePosition)?;
+        let buttons = event.dwButtonState;
+        match event.dwEventFlags {
+            // The second click of a double click is reported like any other click; it's up to the
+            // caller to recognize it.
+            0 | DOUBLE_CLICK if buttons & FROM_LEFT_1ST_BUTTON_PRESSED != 0 => {
+                Some(Click { row, col })
+            }
+            0 | DOUBLE_CLICK if buttons & RIGHTMOST_BUTTON_PRESSED != 0 => {
+                Some(RightClick { row, col })
+            }
+            // The high word of the button state is the (signed) distance that the wheel moved.
+            MOUSE_WHEELED if (buttons as i32) > 0 => Some(WheelUp { row, col }),
+            MOUSE_WHEELED => Some(WheelDown { row, col }),
+            _ => None,
+        }
+    }
+
+    fn read_events(&mut self, timeout: Option<Duration>) -> Vec<Key> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
         let mut buffer = [INPUT_RECORD::default(); 32];
         loop {
+            if let Some(deadline) = deadline {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                let millis = u32::try_from(remaining.as_millis()).unwrap_or(u32::MAX);
+                if unsafe { WaitForSingleObject(self.conin, millis) } != WAIT_OBJECT_0 {
+                    return Vec::new();
+                }
+            }
+
             let mut events_read = 0;
             win32!(ReadConsoleInputW(
                 self.conin,
@@ -181,6 +261,10 @@ impl Terminal {
                             );
                         }
                     }
+                    MOUSE_EVENT => {
+                        let mouse_event = unsafe { record.Event.MouseEvent };
+                        keys.extend(self.mouse_key(&mouse_event));
+                    }
                     WINDOW_BUFFER_SIZE_EVENT => {
                         logging::log_line("[read_events] WINDOW_BUFFER_SIZE_EVENT");
                         keys.push(Resize);
@@ -210,10 +294,21 @@ impl Drop for Terminal {
     }
 }
 
-fn console_winsize(conout: HANDLE) -> Option<(u16, u16)> {
+fn screen_buffer_info(conout: HANDLE) -> Option<CONSOLE_SCREEN_BUFFER_INFO> {
     let mut buffer_info = CONSOLE_SCREEN_BUFFER_INFO::default();
     let result = unsafe { GetConsoleScreenBufferInfo(conout, &raw mut buffer_info) };
-    if result.is_ok() {
+    result.is_ok().then_some(buffer_info)
+}
+
+// Converts a position in the screen buffer into a (row, column) position in the window.
+fn buffer_to_window(buffer_info: &CONSOLE_SCREEN_BUFFER_INFO, pos: COORD) -> Option<(u16, u16)> {
+    let row = u16::try_from(pos.Y - buffer_info.srWindow.Top).ok()?;
+    let col = u16::try_from(pos.X - buffer_info.srWindow.Left).ok()?;
+    Some((row, col))
+}
+
+fn console_winsize(conout: HANDLE) -> Option<(u16, u16)> {
+    if let Some(buffer_info) = screen_buffer_info(conout) {
         // This code specifically computes the size of the window,
         // *not* the size of the buffer (which is easily available
         // from dwSize). I got the algorithm from:
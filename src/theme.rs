@@ -0,0 +1,418 @@
+use clap::ValueEnum;
+
+// The text attributes that can be given in a color spec, along with their SGR codes. A style's
+// attributes are a bitmask of indices into this table.
+const ATTRIBUTES: &[(&str, u8)] = &[
+    ("bold", 1),
+    ("dim", 2),
+    ("italic", 3),
+    ("underline", 4),
+    ("reverse", 7),
+];
+const UNDERLINE: u8 = 1 << 3;
+const REVERSE: u8 = 1 << 4;
+
+const COLOR_NAMES: &[&str] = &[
+    "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Color {
+    // One of the 16 standard terminal colors, where 8-15 are the bright variants of 0-7.
+    Ansi(u8),
+    // One of the 256 colors of the extended palette.
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TextStyle {
+    fg: Option<Color>,
+    bg: Option<Color>,
+    attrs: u8,
+}
+
+// The styles used for each part of the display.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Theme {
+    pub prompt: TextStyle,
+    // The count of matches and choices.
+    pub info: TextStyle,
+    pub spinner: TextStyle,
+    // Matched characters.
+    pub hl: TextStyle,
+    // The highlighted choice.
+    pub cursor: TextStyle,
+    // Matched characters in the highlighted choice, drawn over the `cursor` style.
+    pub hl_cursor: TextStyle,
+    // The pointer shown next to the highlighted choice.
+    pub pointer: TextStyle,
+    // The mark shown next to selected choices.
+    pub marker: TextStyle,
+    // The border around the preview.
+    pub border: TextStyle,
+    // The lines shown above the matches with --header and --header-lines.
+    pub header: TextStyle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Preset {
+    // Red matches, and reverse video for the highlighted choice.
+    Default,
+    // Subdued 256-color schemes for dark and light terminal backgrounds.
+    Dark,
+    Light,
+    // No colors at all.
+    Bw,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Element {
+    Prompt,
+    Info,
+    Spinner,
+    Hl,
+    Cursor,
+    HlCursor,
+    Pointer,
+    Marker,
+    Border,
+    Header,
+}
+
+// One of the comma-separated items given with --color: either the name of a preset, or the name of
+// an element followed by colors and attributes, such as `hl:yellow:bold` or `cursor-bg:236`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColorSpec {
+    Preset(Preset),
+    Element {
+        element: Element,
+        // Whether colors set the background (`ELEMENT-bg:COLOR`) rather than the foreground.
+        background: bool,
+        values: Vec<Value>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    // A color, or None for the terminal's default color.
+    Color(Option<Color>),
+    Attribute(u8),
+    // Clears the attributes set so far.
+    Regular,
+}
+
+impl TextStyle {
+    const fn fg(color: Color) -> TextStyle {
+        TextStyle {
+            fg: Some(color),
+            bg: None,
+            attrs: 0,
+        }
+    }
+
+    const fn attrs(attrs: u8) -> TextStyle {
+        TextStyle {
+            fg: None,
+            bg: None,
+            attrs,
+        }
+    }
+
+    const fn with_bg(self, color: Color) -> TextStyle {
+        TextStyle {
+            bg: Some(color),
+            ..self
+        }
+    }
+
+    // Returns this style with `other` drawn over it.
+    pub fn overlay(&self, other: &TextStyle) -> TextStyle {
+        TextStyle {
+            fg: other.fg.or(self.fg),
+            bg: other.bg.or(self.bg),
+            attrs: self.attrs | other.attrs,
+        }
+    }
+
+    pub fn is_plain(&self) -> bool {
+        *self == TextStyle::default()
+    }
+
+    // Returns the escape sequence that switches to this style, or an empty string if it's plain.
+    pub fn sgr(&self) -> String {
+        let mut codes = Vec::new();
+        for (i, (_, code)) in ATTRIBUTES.iter().enumerate() {
+            if self.attrs & (1 << i) != 0 {
+                codes.push(code.to_string());
+            }
+        }
+        if let Some(fg) = self.fg {
+            codes.push(fg.sgr_params(false));
+        }
+        if let Some(bg) = self.bg {
+            codes.push(bg.sgr_params(true));
+        }
+        if codes.is_empty() {
+            String::new()
+        } else {
+            format!("\x1b[{}m", codes.join(";"))
+        }
+    }
+}
+
+impl Color {
+    fn sgr_params(self, background: bool) -> String {
+        let offset = if background { 10 } else { 0 };
+        match self {
+            Color::Ansi(n) if n < 8 => (30 + offset + n).to_string(),
+            Color::Ansi(n) => (90 + offset + n - 8).to_string(),
+            Color::Indexed(n) => format!("{};5;{}", 38 + offset, n),
+            Color::Rgb(r, g, b) => format!("{};2;{};{};{}", 38 + offset, r, g, b),
+        }
+    }
+}
+
+impl Preset {
+    fn theme(self) -> Theme {
+        use Color::{Ansi, Indexed};
+        match self {
+            Preset::Default => Theme {
+                hl: TextStyle::fg(Ansi(1)),
+                cursor: TextStyle::attrs(REVERSE),
+                hl_cursor: TextStyle::fg(Ansi(1)),
+                ..Theme::default()
+            },
+            Preset::Dark => Theme {
+                prompt: TextStyle::fg(Indexed(110)),
+                info: TextStyle::fg(Indexed(144)),
+                spinner: TextStyle::fg(Indexed(108)),
+                hl: TextStyle::fg(Indexed(108)),
+                cursor: TextStyle::fg(Indexed(254)).with_bg(Indexed(236)),
+                hl_cursor: TextStyle::fg(Indexed(151)),
+                pointer: TextStyle::fg(Indexed(161)),
+                marker: TextStyle::fg(Indexed(168)),
+                border: TextStyle::fg(Indexed(59)),
+                header: TextStyle::fg(Indexed(109)),
+            },
+            Preset::Light => Theme {
+                prompt: TextStyle::fg(Indexed(25)),
+                info: TextStyle::fg(Indexed(101)),
+                spinner: TextStyle::fg(Indexed(161)),
+                hl: TextStyle::fg(Indexed(65)),
+                cursor: TextStyle::fg(Indexed(240)).with_bg(Indexed(251)),
+                hl_cursor: TextStyle::fg(Indexed(66)),
+                pointer: TextStyle::fg(Indexed(161)),
+                marker: TextStyle::fg(Indexed(161)),
+                border: TextStyle::fg(Indexed(145)),
+                header: TextStyle::fg(Indexed(31)),
+            },
+            Preset::Bw => Theme {
+                hl: TextStyle::attrs(UNDERLINE),
+                cursor: TextStyle::attrs(REVERSE),
+                hl_cursor: TextStyle::attrs(UNDERLINE),
+                ..Theme::default()
+            },
+        }
+    }
+}
+
+impl Theme {
+    // Builds the theme from the --color specs, which are applied in order. With `no_color`, the
+    // theme starts out black and white, and any colors in the specs are ignored.
+    pub fn new(specs: &[ColorSpec], no_color: bool) -> Theme {
+        let mut theme = if no_color {
+            Preset::Bw.theme()
+        } else {
+            Preset::Default.theme()
+        };
+        for spec in specs {
+            match spec {
+                ColorSpec::Preset(_) if no_color => {}
+                ColorSpec::Preset(preset) => theme = preset.theme(),
+                ColorSpec::Element {
+                    element,
+                    background,
+                    values,
+                } => {
+                    let style = theme.element_mut(*element);
+                    for value in values {
+                        match *value {
+                            Value::Color(_) if no_color => {}
+                            // A background color takes the place of reverse video.
+                            Value::Color(color) if *background => {
+                                style.bg = color;
+                                style.attrs &= !REVERSE;
+                            }
+                            Value::Color(color) => style.fg = color,
+                            Value::Attribute(attr) => style.attrs |= attr,
+                            Value::Regular => style.attrs = 0,
+                        }
+                    }
+                }
+            }
+        }
+        theme
+    }
+
+    fn element_mut(&mut self, element: Element) -> &mut TextStyle {
+        match element {
+            Element::Prompt => &mut self.prompt,
+            Element::Info => &mut self.info,
+            Element::Spinner => &mut self.spinner,
+            Element::Hl => &mut self.hl,
+            Element::Cursor => &mut self.cursor,
+            Element::HlCursor => &mut self.hl_cursor,
+            Element::Pointer => &mut self.pointer,
+            Element::Marker => &mut self.marker,
+            Element::Border => &mut self.border,
+            Element::Header => &mut self.header,
+        }
+    }
+}
+
+pub fn parse_color_spec(spec: &str) -> Result<ColorSpec, String> {
+    let mut parts = spec.split(':');
+    let name = parts.next().unwrap_or_default();
+    let Some(first_value) = parts.next() else {
+        return Preset::from_str(name, true)
+            .map(ColorSpec::Preset)
+            .map_err(|_| format!("unknown color scheme '{name}'"));
+    };
+    let (name, background) = match name.strip_suffix("-bg") {
+        Some(name) => (name, true),
+        None => (name, false),
+    };
+    let element =
+        Element::from_str(name, true).map_err(|_| format!("unknown color element '{name}'"))?;
+    let values = std::iter::once(first_value)
+        .chain(parts)
+        .map(parse_value)
+        .collect::<Result<_, _>>()?;
+    Ok(ColorSpec::Element {
+        element,
+        background,
+        values,
+    })
+}
+
+fn parse_value(value: &str) -> Result<Value, String> {
+    let lower = value.to_lowercase();
+    if lower == "regular" {
+        return Ok(Value::Regular);
+    }
+    if let Some(i) = ATTRIBUTES.iter().position(|(name, _)| *name == lower) {
+        return Ok(Value::Attribute(1 << i));
+    }
+    parse_color(&lower)
+        .map(Value::Color)
+        .ok_or_else(|| format!("invalid color or attribute '{value}'"))
+}
+
+// Parses a color name (such as `red` or `bright-red`), a palette index from 0 to 255, or a hex RGB
+// color such as `#ff8000`. `default` is the terminal's default color, which is returned as None.
+fn parse_color(color: &str) -> Option<Option<Color>> {
+    if color == "default" || color == "-1" {
+        return Some(None);
+    }
+    if let Some(hex) = color.strip_prefix('#') {
+        let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+        return match hex.len() {
+            6 => Some(Some(Color::Rgb(channel(0)?, channel(2)?, channel(4)?))),
+            _ => None,
+        };
+    }
+    if let Ok(n) = color.parse::<u8>() {
+        return Some(Some(Color::Indexed(n)));
+    }
+    let (name, bright) = match color.strip_prefix("bright-") {
+        Some(name) => (name, 8),
+        None => (color, 0),
+    };
+    let n = COLOR_NAMES.iter().position(|c| *c == name)?;
+    Some(Some(Color::Ansi(n as u8 + bright)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Color, ColorSpec, Element, Preset, TextStyle, Theme, Value, parse_color_spec};
+
+    fn theme(specs: &str, no_color: bool) -> Theme {
+        let specs: Vec<_> = specs
+            .split(',')
+            .map(|spec| parse_color_spec(spec).unwrap())
+            .collect();
+        Theme::new(&specs, no_color)
+    }
+
+    #[test]
+    fn color_spec_parsing() {
+        assert_eq!(
+            parse_color_spec("dark"),
+            Ok(ColorSpec::Preset(Preset::Dark))
+        );
+        assert_eq!(
+            parse_color_spec("hl-cursor:bright-yellow:bold"),
+            Ok(ColorSpec::Element {
+                element: Element::HlCursor,
+                background: false,
+                values: vec![Value::Color(Some(Color::Ansi(11))), Value::Attribute(1)],
+            })
+        );
+        assert_eq!(
+            parse_color_spec("cursor-bg:#1a2B3c"),
+            Ok(ColorSpec::Element {
+                element: Element::Cursor,
+                background: true,
+                values: vec![Value::Color(Some(Color::Rgb(0x1a, 0x2b, 0x3c)))],
+            })
+        );
+        assert!(parse_color_spec("solarized").is_err());
+        assert!(parse_color_spec("query:red").is_err());
+        assert!(parse_color_spec("hl:256").is_err());
+        assert!(parse_color_spec("hl:#12345").is_err());
+        assert!(parse_color_spec("hl:").is_err());
+    }
+
+    #[test]
+    fn escape_sequences() {
+        assert_eq!(TextStyle::default().sgr(), "");
+        let theme = theme(
+            "hl:yellow,cursor-bg:236,marker:green:bold,prompt:#ff8000",
+            false,
+        );
+        assert_eq!(theme.hl.sgr(), "\x1b[33m");
+        assert_eq!(theme.cursor.sgr(), "\x1b[48;5;236m");
+        assert_eq!(theme.marker.sgr(), "\x1b[1;32m");
+        assert_eq!(theme.prompt.sgr(), "\x1b[38;2;255;128;0m");
+        assert_eq!(
+            theme.cursor.overlay(&theme.hl_cursor).sgr(),
+            "\x1b[31;48;5;236m"
+        );
+    }
+
+    #[test]
+    fn presets_and_overrides() {
+        let default = Theme::new(&[], false);
+        assert_eq!(default.cursor.sgr(), "\x1b[7m");
+        assert_eq!(
+            default.cursor.overlay(&default.hl_cursor).sgr(),
+            "\x1b[7;31m"
+        );
+        assert_eq!(theme("dark", false).cursor.sgr(), "\x1b[38;5;254;48;5;236m");
+        assert_eq!(theme("hl:blue,light", false), theme("light", false));
+        assert_eq!(
+            theme("cursor:regular:underline", false).cursor.sgr(),
+            "\x1b[4m"
+        );
+        assert_eq!(theme("hl:default", false).hl.sgr(), "");
+    }
+
+    #[test]
+    fn no_color() {
+        let theme = theme("dark,hl:yellow:bold,cursor-bg:236", true);
+        assert_eq!(theme.hl.sgr(), "\x1b[1;4m");
+        assert_eq!(theme.cursor.sgr(), "\x1b[7m");
+        assert_eq!(theme.prompt.sgr(), "");
+    }
+}